@@ -0,0 +1,372 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use hidapi::{HidApi, HidDevice};
+
+use crate::faults::FlagMap;
+use crate::frame::{self, FrameError, MSG_LEN};
+use crate::memory::{MemoryBlock, LIVE};
+use crate::sim::{Script, SimTransport};
+use crate::transport::{ReplayTransport, Transport};
+use crate::{PID, VID};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(200); // longest wait for a response, a blocking read hangs if the controller powers off
+const MAX_FAILURES: u32 = 10; // consecutive failed transfers before the controller is treated as offline
+
+// usb descriptor strings of the open device
+#[derive(Clone, Default)]
+pub struct UsbInfo {
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>, // usb device release number, bcd
+    pub model: Model,
+}
+
+// controller family, from the usb product string
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum Model {
+    Sr,
+    Xct,
+    Axe,
+    Spm,
+    #[default]
+    Unknown,
+}
+
+impl Model {
+    // the family name appears in the product string, e.g. "Alltrax XCT"
+    pub fn from_product(product: &str) -> Model {
+        let product = product.to_uppercase();
+        let words: Vec<&str> = product.split(|c: char| !c.is_ascii_alphanumeric()).collect();
+        let has = |family: &str| words.iter().any(|w| w.starts_with(family));
+
+        if has("XCT") {
+            Model::Xct
+        } else if has("AXE") {
+            Model::Axe
+        } else if has("SPM") {
+            Model::Spm
+        } else if has("SR") {
+            Model::Sr
+        } else {
+            Model::Unknown
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::Sr => "SR",
+            Model::Xct => "XCT",
+            Model::Axe => "AXE",
+            Model::Spm => "SPM",
+            Model::Unknown => "unknown",
+        }
+    }
+
+    // live block field map for the model, only the SR map is known so the others are read with it until they are captured
+    pub fn live(&self) -> &'static MemoryBlock {
+        match self {
+            Model::Sr | Model::Xct | Model::Axe | Model::Spm | Model::Unknown => &LIVE,
+        }
+    }
+}
+
+pub enum TransferError {
+    Offline,
+    Timeout, // no response before RESPONSE_TIMEOUT
+    Hid(String), // usb error, usually the device was unplugged
+    Frame(FrameError),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::Offline => write!(f, "controller offline"),
+            TransferError::Timeout => write!(f, "no response"),
+            TransferError::Hid(e) => write!(f, "usb error: {e}"),
+            TransferError::Frame(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+// a response that passed the length and id checks
+pub struct Response {
+    pub stale: u32, // late responses to earlier requests that were skipped
+    pub layout: Result<(), FrameError>, // unconfirmed address and checksum checks
+}
+
+impl Response {
+    // reject a response whose address echo or checksum doesn't match
+    pub fn strict(self) -> Result<Response, TransferError> {
+        match self.layout {
+            Ok(_) => Ok(self),
+            Err(e) => Err(TransferError::Frame(e)),
+        }
+    }
+}
+
+// send a request and wait for its response, checking the framing
+pub fn transfer(device: &mut dyn Transport, request: &[u8; MSG_LEN], buf: &mut [u8; MSG_LEN]) -> Result<Response, TransferError> {
+    device.write(request).map_err(TransferError::Hid)?;
+
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let mut stale = 0;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let len = device.read_timeout(buf, remaining).map_err(TransferError::Hid)?;
+        if len == 0 {
+            return Err(TransferError::Timeout);
+        }
+
+        match frame::validate_response(request, buf, len) {
+            Ok(_) => return Ok(Response { stale, layout: frame::check_layout(request, buf) }),
+            // a late response to a request that already timed out, keep waiting for ours
+            Err(FrameError::WrongId(_)) if !remaining.is_zero() => stale += 1,
+            Err(e) => return Err(TransferError::Frame(e)),
+        }
+    }
+}
+
+// open the first controller on the bus, for the command line tools
+pub fn open() -> Result<HidDevice, String> {
+    let api = HidApi::new().map_err(|e| format!("{e}"))?;
+    api.open(VID, PID).map_err(|e| format!("Failed to open controller: {e}"))
+}
+
+// where the service gets its responses from
+pub enum Source {
+    Hid { serial: Option<String> }, // only open the controller with this serial number
+    Replay(String),                 // capture file
+    Simulate { script: String, flags: FlagMap }, // script file and the flag names it uses
+}
+
+// find the controller by VID/PID and optional serial number and open it
+fn open_hid(api: Option<&mut HidApi>, serial: Option<&str>) -> Result<(Box<dyn Transport>, UsbInfo), String> {
+    let Some(api) = api else {
+        return Err("hidapi not started".to_string());
+    };
+    api.refresh_devices().map_err(|e| format!("Failed to enumerate usb devices: {e}"))?;
+
+    let info = api
+        .device_list()
+        .find(|d| d.vendor_id() == VID && d.product_id() == PID && (serial.is_none() || d.serial_number() == serial))
+        .ok_or("Controller not found, waiting for it to come online")?;
+
+    let device = info.open_device(api).map_err(|e| format!("Failed to open controller: {e}"))?;
+
+    let release = info.release_number();
+    let usb = UsbInfo {
+        product: info.product_string().map(String::from),
+        serial: info.serial_number().map(String::from),
+        firmware: Some(format!("{:x}.{:02x}", release >> 8, release & 0xFF)),
+        model: info.product_string().map_or(Model::Unknown, Model::from_product),
+    };
+    println!(
+        "Controller online, {} ({} model), firmware {}, serial {}",
+        usb.product.as_deref().unwrap_or("unknown product"),
+        usb.model.name(),
+        usb.firmware.as_deref().unwrap_or("unknown"),
+        usb.serial.as_deref().unwrap_or("unknown")
+    );
+
+    Ok((Box::new(device), usb))
+}
+
+// connection to the controller, reopened when it comes back after an unplug or key off
+pub struct Controller {
+    api: Option<HidApi>, // only used for a usb source
+    source: Source,
+    device: Option<Box<dyn Transport>>,
+    usb: UsbInfo, // descriptor strings of the open usb device
+    strict_frames: bool, // drop frames failing the unconfirmed address and checksum checks
+    failures: u32,
+    reported_missing: bool, // only log a missing controller once per offline period
+}
+
+impl Controller {
+    pub fn new(source: Source, strict_frames: bool) -> Result<Controller, String> {
+        let api = match source {
+            Source::Hid { .. } => Some(HidApi::new().map_err(|e| format!("Failed to start hidapi: {e}"))?),
+            _ => None,
+        };
+        Ok(Controller { api, source, device: None, usb: UsbInfo::default(), strict_frames, failures: 0, reported_missing: false })
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.device.is_some()
+    }
+
+    // open the controller, re-enumerating the usb bus, true if it is now online
+    pub fn connect(&mut self) -> bool {
+        if self.device.is_some() {
+            return true;
+        }
+
+        let device = match &self.source {
+            Source::Hid { serial } => open_hid(self.api.as_mut(), serial.as_deref()),
+            Source::Replay(path) => ReplayTransport::load(path).map(|t| (Box::new(t) as Box<dyn Transport>, UsbInfo::default())),
+            Source::Simulate { script, flags } => {
+                Script::load(script, flags).map(|s| (Box::new(SimTransport::new(s, flags.clone())) as Box<dyn Transport>, UsbInfo::default()))
+            }
+        };
+
+        match device {
+            Ok((device, usb)) => {
+                self.device = Some(device);
+                self.usb = usb;
+                self.failures = 0;
+                self.reported_missing = false;
+                true
+            }
+            Err(e) => {
+                if !self.reported_missing {
+                    println!("{e}");
+                    self.reported_missing = true;
+                }
+                false
+            }
+        }
+    }
+
+    pub fn usb_info(&self) -> UsbInfo {
+        self.usb.clone()
+    }
+
+    // transfer on the open device, dropping it on a usb error or when it stops responding
+    pub fn transfer(&mut self, request: &[u8; MSG_LEN], buf: &mut [u8; MSG_LEN]) -> Result<Response, TransferError> {
+        let Some(device) = self.device.as_mut() else {
+            return Err(TransferError::Offline);
+        };
+
+        let mut result = transfer(device.as_mut(), request, buf);
+        if self.strict_frames {
+            result = result.and_then(Response::strict);
+        }
+        match &result {
+            Ok(_) => self.failures = 0,
+            Err(TransferError::Hid(_)) => self.disconnect(),
+            Err(_) => {
+                self.failures += 1;
+                if self.failures >= MAX_FAILURES {
+                    println!("Controller stopped responding");
+                    self.disconnect();
+                }
+            }
+        }
+
+        result
+    }
+
+    fn disconnect(&mut self) {
+        self.device = None;
+        self.failures = 0;
+    }
+}
+
+impl UsbInfo {
+    // influxdb line protocol, string fields are quoted
+    pub fn line(&self) -> String {
+        format!(
+            "motor_controller_info,device=alltrax product=\"{}\",model=\"{}\",firmware=\"{}\",serial=\"{}\"",
+            self.product.as_deref().unwrap_or("unknown"),
+            self.model.name(),
+            self.firmware.as_deref().unwrap_or("unknown"),
+            self.serial.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::LIVE;
+
+    // answers each read with the next queued frame, then times out
+    struct QueuedTransport {
+        frames: Vec<[u8; MSG_LEN]>,
+    }
+
+    impl Transport for QueuedTransport {
+        fn write(&mut self, _request: &[u8; MSG_LEN]) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn read_timeout(&mut self, buf: &mut [u8; MSG_LEN], _timeout: Duration) -> Result<usize, String> {
+            if self.frames.is_empty() {
+                return Ok(0);
+            }
+            *buf = self.frames.remove(0);
+            Ok(MSG_LEN)
+        }
+    }
+
+    fn response(request: &[u8; MSG_LEN]) -> [u8; MSG_LEN] {
+        let mut buf = [0u8; MSG_LEN];
+        LIVE.field("battery_voltage").unwrap().set(&mut buf, 48.5);
+        frame::seal_response(request, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn transfer_skips_stale_response() {
+        let request = LIVE.request();
+        let mut stale = response(&request);
+        stale[1] = 0x2F; // answer to a different command
+
+        let mut device = QueuedTransport { frames: vec![stale, response(&request)] };
+        let mut buf = [0u8; MSG_LEN];
+
+        let result = transfer(&mut device, &request, &mut buf).ok().unwrap();
+        assert_eq!(result.stale, 1);
+        assert!(result.layout.is_ok());
+        assert_eq!(LIVE.field("battery_voltage").unwrap().value(&buf), 48.5);
+    }
+
+    #[test]
+    fn strict_frames_drop_checksum_mismatch() {
+        let request = LIVE.request();
+        let mut frame = response(&request);
+        frame[2] ^= 0xFF; // corrupt the checksum
+        let mut buf = [0u8; MSG_LEN];
+
+        let mut device = QueuedTransport { frames: vec![frame] };
+        let result = transfer(&mut device, &request, &mut buf).ok().unwrap();
+        let Err(e) = &result.layout else { panic!("checksum mismatch not detected") };
+        assert!(matches!(e, FrameError::Checksum { .. }));
+
+        let mut stats = frame::FrameStats::default();
+        stats.record_unverified(e);
+        assert_eq!((stats.ok, stats.unverified, stats.rejected, stats.checksum), (0, 1, 0, 1));
+
+        let mut device = QueuedTransport { frames: vec![frame] };
+        let result = transfer(&mut device, &request, &mut buf).and_then(Response::strict);
+        let Err(TransferError::Frame(e)) = result else { panic!("mismatched frame accepted") };
+
+        let mut stats = frame::FrameStats::default();
+        stats.record(&Err(e));
+        assert_eq!((stats.ok, stats.unverified, stats.rejected, stats.checksum), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn model_from_product_string() {
+        assert_eq!(Model::from_product("Alltrax XCT"), Model::Xct);
+        assert_eq!(Model::from_product("AXE4834"), Model::Axe);
+        assert_eq!(Model::from_product("Alltrax SPM"), Model::Spm);
+        assert_eq!(Model::from_product("SR 48300"), Model::Sr);
+        assert_eq!(Model::from_product("Alltrax Controller"), Model::Unknown);
+    }
+
+    #[test]
+    fn transfer_times_out_without_response() {
+        let request = LIVE.request();
+        let mut buf = [0u8; MSG_LEN];
+
+        let mut silent = QueuedTransport { frames: Vec::new() };
+        assert!(matches!(transfer(&mut silent, &request, &mut buf), Err(TransferError::Timeout)));
+
+        let mut wrong = response(&request);
+        wrong[1] = 0x2F;
+        let mut late = QueuedTransport { frames: vec![wrong] };
+        assert!(matches!(transfer(&mut late, &request, &mut buf), Err(TransferError::Timeout)));
+    }
+}
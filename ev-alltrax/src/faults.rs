@@ -0,0 +1,136 @@
+use std::fs;
+
+use crate::frame::MSG_LEN;
+use crate::memory::MemoryBlock;
+
+// live block words that look like fault and status bits, published raw until their bits are mapped from captures
+pub const FLAG_WORDS: [&str; 2] = ["uk_42_43", "uk_44_45"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FlagKind {
+    Fault,  // listed on live/motor_controller/faults while set
+    Status,
+}
+
+#[derive(Clone)]
+pub struct Flag {
+    pub name: String,
+    pub kind: FlagKind,
+    pub field: &'static str, // field name in the live block
+    pub mask: u16,
+}
+
+// named bits of the live block, loaded from a file as they are mapped, none by default
+#[derive(Clone, Default)]
+pub struct FlagMap {
+    flags: Vec<Flag>,
+}
+
+pub struct FlagEvent {
+    pub name: String,
+    pub raised: bool,
+}
+
+// tracks active faults and status flags between reads
+#[derive(Default)]
+pub struct FaultMonitor {
+    flags: FlagMap,
+    active: Vec<String>,
+}
+
+impl FlagMap {
+    pub fn load(path: &str, live: &MemoryBlock) -> Result<FlagMap, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        let map = FlagMap::parse(&text, live).map_err(|e| format!("{path}: {e}"))?;
+
+        println!("Loaded {} flags from {path}", map.flags.len());
+        Ok(map)
+    }
+
+    // name = fault|status field mask, # starts a comment
+    pub fn parse(text: &str, live: &MemoryBlock) -> Result<FlagMap, String> {
+        let mut flags: Vec<Flag> = Vec::new();
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("Invalid line {}: {line}", line_number + 1);
+
+            let (name, definition) = line.split_once('=').ok_or_else(error)?;
+            let name = name.trim();
+            let words: Vec<&str> = definition.split_whitespace().collect();
+            let [kind, field, mask] = words.as_slice() else { return Err(error()) };
+
+            let kind = match *kind {
+                "fault" => FlagKind::Fault,
+                "status" => FlagKind::Status,
+                _ => return Err(error()),
+            };
+            let field = live.field(field).ok_or_else(|| format!("Unknown field on line {}: {field}", line_number + 1))?.name;
+            let mask = u16::from_str_radix(mask.strip_prefix("0x").unwrap_or(mask), 16).map_err(|_| error())?;
+
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ',') || flags.iter().any(|f| f.name == name) {
+                return Err(error());
+            }
+            flags.push(Flag { name: name.to_string(), kind, field, mask });
+        }
+
+        Ok(FlagMap { flags })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Flag> {
+        self.flags.iter().find(|f| f.name == name)
+    }
+}
+
+impl FaultMonitor {
+    pub fn new(flags: FlagMap) -> FaultMonitor {
+        FaultMonitor { flags, active: Vec::new() }
+    }
+
+    // the raw flag words and mapped flags as line protocol fields, and any flags raised or cleared since the last read
+    pub fn update(&mut self, live: &MemoryBlock, buf: &[u8; MSG_LEN]) -> (String, Vec<FlagEvent>) {
+        let mut fields = Vec::new();
+        let mut events = Vec::new();
+        let mut active = Vec::new();
+
+        for name in FLAG_WORDS {
+            if let Some(field) = live.field(name) {
+                fields.push(format!("{name}={}", field.raw(buf) as u16));
+            }
+        }
+
+        for flag in self.flags.flags.iter() {
+            let Some(field) = live.field(flag.field) else { continue };
+            let set = field.raw(buf) as u16 & flag.mask == flag.mask; //check if flag bit is 1
+            fields.push(format!("{}={}", flag.name, set as u8));
+
+            if set {
+                active.push(flag.name.clone());
+            }
+            if set != self.active.contains(&flag.name) {
+                events.push(FlagEvent { name: flag.name.clone(), raised: set });
+            }
+        }
+        self.active = active;
+
+        (fields.join(","), events)
+    }
+
+    // comma separated list of active faults, an empty retained message would clear the topic so use none instead
+    pub fn active_faults(&self) -> String {
+        let faults: Vec<&str> = self
+            .active
+            .iter()
+            .filter(|name| self.flags.find(name).is_some_and(|f| f.kind == FlagKind::Fault))
+            .map(|name| name.as_str())
+            .collect();
+
+        if faults.is_empty() {
+            return "none".to_string();
+        }
+        faults.join(",")
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+
+use crate::bytes_to_word_unsigned;
+
+pub const MSG_LEN: usize = 64; // hid report size, request and response
+
+// offsets into a 64 byte response
+const RESP_ID: usize = 1; // message id, echoes the request command
+const RESP_CHECKSUM: usize = 2; // 16 bit checksum, big endian
+const RESP_ADDRESS: usize = 4; // echo of the requested address, big endian
+const RESP_CHECKSUM_START: usize = 4; // checksum covers the address echo and the data
+
+// offsets into a 64 byte request
+const REQ_ID: usize = 1; // command
+const REQ_ADDRESS: usize = 2; // address to read, big endian
+
+pub enum FrameError {
+    Short(usize), // bytes read
+    WrongId(u8),
+    AddressMismatch { requested: u16, received: u16 },
+    Checksum { expected: u16, calculated: u16 },
+}
+
+// counts of accepted and rejected responses
+#[derive(Default)]
+pub struct FrameStats {
+    pub ok: u32, // passed every check
+    pub unverified: u32, // used although the address echo or checksum didn't match, without --strict-frames
+    pub rejected: u32,
+    pub short: u32,
+    pub wrong_id: u32,
+    pub address_mismatch: u32, // rejected or unverified, the address echo is not confirmed yet
+    pub checksum: u32,         // rejected or unverified, the checksum is not confirmed yet
+    pub stale: u32, // late responses to earlier requests, skipped while waiting for the right one
+    pub timeouts: u32, // no response at all, counted apart from rejected frames
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Short(len) => write!(f, "short frame, {len} of {MSG_LEN} bytes"),
+            FrameError::WrongId(id) => write!(f, "unexpected message id {id:#04x}"),
+            FrameError::AddressMismatch { requested, received } => write!(f, "address {received:#06x} does not match request {requested:#06x}"),
+            FrameError::Checksum { expected, calculated } => write!(f, "checksum {expected:#06x} does not match calculated {calculated:#06x}"),
+        }
+    }
+}
+
+impl FrameStats {
+    // a frame that was used or dropped
+    pub fn record(&mut self, result: &Result<(), FrameError>) {
+        let Err(e) = result else {
+            self.ok += 1;
+            return;
+        };
+        self.rejected += 1;
+        self.count(e);
+    }
+
+    // a frame used despite a layout mismatch
+    pub fn record_unverified(&mut self, e: &FrameError) {
+        self.unverified += 1;
+        self.count(e);
+    }
+
+    fn count(&mut self, e: &FrameError) {
+        match e {
+            FrameError::Short(_) => self.short += 1,
+            FrameError::WrongId(_) => self.wrong_id += 1,
+            FrameError::AddressMismatch { .. } => self.address_mismatch += 1,
+            FrameError::Checksum { .. } => self.checksum += 1,
+        }
+    }
+
+    // influxdb line protocol fields
+    pub fn fields(&self) -> String {
+        format!(
+            "frames_ok={},frames_unverified={},frames_rejected={},frames_short={},frames_wrong_id={},frames_address_mismatch={},frames_checksum={},frames_stale={},frames_timeout={}",
+            self.ok,
+            self.unverified,
+            self.rejected,
+            self.short,
+            self.wrong_id,
+            self.address_mismatch,
+            self.checksum,
+            self.stale,
+            self.timeouts
+        )
+    }
+}
+
+// 16 bit sum of the address echo and data bytes, a guess that captures have not confirmed
+pub fn checksum(buf: &[u8; MSG_LEN]) -> u16 {
+    buf[RESP_CHECKSUM_START..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16))
+}
+
+// fill in the id, address echo and checksum of a response to a request, used by the replay and simulated transports
+pub fn seal_response(request: &[u8; MSG_LEN], buf: &mut [u8; MSG_LEN]) {
+    buf[RESP_ID] = request[REQ_ID];
+    buf[RESP_ADDRESS..RESP_ADDRESS + 2].copy_from_slice(&request[REQ_ADDRESS..REQ_ADDRESS + 2]);
+
+    let sum = checksum(buf);
+    buf[RESP_CHECKSUM..RESP_CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+// check a response against the request that produced it, the length and id are the checks the service always made
+pub fn validate_response(request: &[u8; MSG_LEN], buf: &[u8; MSG_LEN], len: usize) -> Result<(), FrameError> {
+    if len < MSG_LEN {
+        return Err(FrameError::Short(len));
+    }
+
+    if buf[RESP_ID] != request[REQ_ID] {
+        return Err(FrameError::WrongId(buf[RESP_ID]));
+    }
+
+    Ok(())
+}
+
+// address echo and checksum, neither layout has been confirmed against a capture yet
+// so a mismatch only drops the frame with --strict-frames
+pub fn check_layout(request: &[u8; MSG_LEN], buf: &[u8; MSG_LEN]) -> Result<(), FrameError> {
+    let requested = bytes_to_word_unsigned(request[REQ_ADDRESS + 1], request[REQ_ADDRESS]);
+    let received = bytes_to_word_unsigned(buf[RESP_ADDRESS + 1], buf[RESP_ADDRESS]);
+    if requested != received {
+        return Err(FrameError::AddressMismatch { requested, received });
+    }
+
+    let expected = bytes_to_word_unsigned(buf[RESP_CHECKSUM + 1], buf[RESP_CHECKSUM]);
+    let calculated = checksum(buf);
+    if expected != calculated {
+        return Err(FrameError::Checksum { expected, calculated });
+    }
+
+    Ok(())
+}
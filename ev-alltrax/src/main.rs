@@ -1,117 +1,308 @@
 use mqtt::Client;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{process, thread};
 use std::str;
+use std::env;
 
 extern crate paho_mqtt as mqtt;
 extern crate hidapi;
 
+mod device;
+mod faults;
+mod frame;
+mod memory;
+mod metrics;
+mod settings;
+mod sim;
+mod transport;
+mod workbench;
+
+use device::{Controller, Source, TransferError, UsbInfo};
+use frame::{FrameStats, MSG_LEN};
+use faults::{FaultMonitor, FlagMap};
+use memory::{MemoryBlock, LIVE};
+use metrics::{DriveMetrics, Totals};
+
 const MQTT_IP: &str = "tcp://127.0.0.1:1883";
 const MQTT_CLIENT_ID: &str = "alltrax";
-const MSG_REQUEST: [u8; 64] = [0x01, 0x2E, 0xA0, 0x00, 0x20, 0x00, 0x41, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
 const VID: u16 = 0x23D4;
 const PID: u16 = 0x0001;
+const FRAME_STATS_RATE: Duration = Duration::from_secs(10); // rate to report accepted and rejected frames
+const RECONNECT_RATE: Duration = Duration::from_secs(2); // time between searches for the controller while it is offline
+const STATUS_TOPIC: &str = "live/motor_controller/status";
+const MAX_RATE_HZ: f64 = 50.0; // a read takes about 10ms on the usb bus, faster rates just queue requests
 
 fn main() {
-    let mqtt_client = open_mqtt_connection();
+    let mut args: Vec<String> = env::args().collect();
 
-    let api = hidapi::HidApi::new().unwrap();
+    // only connect to the controller with this usb serial number
+    let serial = take_flag(&mut args, "--serial", "a usb serial number");
 
-    // Connect to device using its VID and PID
-    let device = api.open(VID, PID).unwrap();
-    let mut buf = [0u8; 64];
+    // names for bits of the live block, none until they have been mapped from captures
+    let flags = match take_flag(&mut args, "--flags", "a flag map file") {
+        Some(path) => FlagMap::load(&path, &LIVE).unwrap_or_else(|e| {
+            println!("{e}");
+            process::exit(1);
+        }),
+        None => FlagMap::default(),
+    };
+
+    // run without the controller from a capture or a simulation script
+    let replay = take_flag(&mut args, "--replay", "a capture file");
+    let simulate = take_flag(&mut args, "--simulate", "a script file");
+    let source = match (replay, simulate) {
+        (Some(path), None) => Source::Replay(path),
+        (None, Some(path)) => Source::Simulate { script: path, flags: flags.clone() },
+        (None, None) => Source::Hid { serial },
+        _ => {
+            println!("--replay and --simulate can not be used together");
+            process::exit(1);
+        }
+    };
+
+    // live block poll rate, throttle and current transients need 20hz or more
+    let mut live_period = None;
+    if let Some(rate) = take_flag(&mut args, "--rate-hz", "a rate in hz") {
+        let rate: f64 = match rate.parse() {
+            Ok(rate) if rate > 0.0 && rate <= MAX_RATE_HZ => rate,
+            _ => {
+                println!("--rate-hz must be greater than 0 and at most {MAX_RATE_HZ}");
+                process::exit(1);
+            }
+        };
+        live_period = Some(Duration::from_secs_f64(1.0 / rate));
+    }
+
+    // drop frames whose address echo or checksum doesn't match, both layouts are unconfirmed so frames are used by default
+    let strict_frames = take_switch(&mut args, "--strict-frames");
+
+    // blocks to read besides live, e.g. fault_log,settings
+    let extra_blocks: Vec<String> = take_flag(&mut args, "--blocks", "a comma separated list of blocks")
+        .map(|list| list.split(',').map(|name| name.trim().to_string()).collect())
+        .unwrap_or_default();
+    if let Some(name) = extra_blocks.iter().find(|name| !memory::OPTIONAL_BLOCKS.iter().any(|b| b.name == name.as_str())) {
+        let names: Vec<&str> = memory::OPTIONAL_BLOCKS.iter().map(|b| b.name).collect();
+        println!("Unknown block {name}, expected one of {}", names.join(", "));
+        process::exit(1);
+    }
+
+    if args.len() > 1 {
+        let args: Vec<&str> = args[1..].iter().map(|a| a.as_str()).collect();
+        let result = match args.as_slice() {
+            ["capture", path] => workbench::capture(path),
+            ["analyze", path] => workbench::analyze(path),
+            ["settings", "dump", path] => settings::dump(path),
+            ["settings", "diff", path] => settings::diff(path),
+            ["settings", "write", path, settings::UNCONFIRMED_FLAG] => settings::write(path, false),
+            ["settings", "write", path, settings::UNCONFIRMED_FLAG, "--yes"] => settings::write(path, true),
+            ["settings", "write", _, ..] => Err(format!("The settings write command is not confirmed, pass {} to use it", settings::UNCONFIRMED_FLAG)),
+            _ => Err("Usage: ev-alltrax [capture <file> | analyze <file> | settings dump|diff <file> | settings write <file> --unconfirmed [--yes]]".to_string()),
+        };
+
+        if let Err(e) = result {
+            println!("{e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mqtt_client = open_mqtt_connection(MQTT_CLIENT_ID);
+
+    let mut controller = Controller::new(source, strict_frames).unwrap_or_else(|e| {
+        println!("{e}");
+        process::exit(1);
+    });
+    let mut buf = [0u8; MSG_LEN];
+
+    let mut frame_stats = FrameStats::default();
+    let mut last_stats = Instant::now();
+
+    let mut fault_monitor = FaultMonitor::new(flags);
+    write_mqtt_retained(&mqtt_client, "live/motor_controller/faults", fault_monitor.active_faults().as_str()); //no faults until the first read
+    write_mqtt_retained(&mqtt_client, STATUS_TOPIC, "offline"); //offline until the controller is found
+
+    let mut blocks = poll_blocks(&extra_blocks);
+    let periods = poll_periods(&blocks, live_period);
+    let mut drive_metrics = DriveMetrics::new(&LIVE);
+
+    let mut next_read: Vec<Instant> = blocks.iter().map(|_| Instant::now()).collect(); // when each block is next due
 
     loop{
-        let res = device.write(&MSG_REQUEST).unwrap(); //write request message
-        thread::sleep(Duration::from_millis(50)); //wait 50ms
-
-        let res = device.read(&mut buf[..]).unwrap(); //read from device
-        //println!("Read: {:?}", &buf[..res]);
-
-        let message_id = buf[1];
-        let checksum = bytes_to_word_unsigned(buf[3], buf[2]);
-
-        if message_id == 0x2E{
-            let battery_voltage = (bytes_to_word_unsigned(buf[8], buf[9]) as f32) / 10.0;
-            let throttle_pointer = bytes_to_word_unsigned(buf[14], buf[15]);
-            let throttle_position = bytes_to_word_unsigned(buf[16], buf[17]);
-            let motor_current = (bytes_to_word_signed(buf[18], buf[19]) as f32) / 10.0;
-            let overtemp_cap = bytes_to_word_signed(buf[46], buf[47]);
-
-            let uk_6_7 = bytes_to_word_signed(buf[6], buf[7]);
-            let uk_10_11 = (bytes_to_word_signed(buf[10], buf[11]) as f32) / 10.0;
-            let uk_12_13 = bytes_to_word_signed(buf[12], buf[13]);
-            let uk_20_21 = bytes_to_word_signed(buf[20], buf[21]);
-            let uk_22_23 = bytes_to_word_signed(buf[22], buf[23]);
-            let uk_24_25 = bytes_to_word_signed(buf[24], buf[25]);
-            let uk_26_27 = bytes_to_word_signed(buf[26], buf[27]);
-            let uk_28_29 = bytes_to_word_signed(buf[28], buf[29]);
-            let uk_30_31 = bytes_to_word_signed(buf[30], buf[31]);
-            let uk_32_33 = bytes_to_word_signed(buf[32], buf[33]);
-            let uk_34_35 = bytes_to_word_signed(buf[34], buf[35]);
-            let uk_36_37 = bytes_to_word_signed(buf[36], buf[37]);
-            let uk_38_39 = bytes_to_word_signed(buf[38], buf[39]);
-            let uk_40_41 = bytes_to_word_signed(buf[40], buf[41]);
-            let uk_42_43 = bytes_to_word_signed(buf[42], buf[43]);
-            let uk_44_45 = bytes_to_word_signed(buf[44], buf[45]);
-            
-            let uk_48_49 = bytes_to_word_signed(buf[48], buf[49]);
-            let uk_50_51 = bytes_to_word_signed(buf[50], buf[51]);
-            let uk_52_53 = bytes_to_word_signed(buf[52], buf[53]);
-            let uk_54_55 = bytes_to_word_signed(buf[54], buf[55]);
-            let uk_56_57 = bytes_to_word_signed(buf[56], buf[57]);
-            let uk_58_59 = bytes_to_word_signed(buf[58], buf[59]);
-            let uk_60_61 = bytes_to_word_signed(buf[60], buf[61]);
-            let uk_62_63 = bytes_to_word_signed(buf[62], buf[63]);
-
-            /*println!("Battery Voltage:\t{battery_voltage}");
-            println!("Motor Current:\t\t{motor_current}");
-            println!("Throttle Pointer:\t{throttle_pointer}");
-            println!("Throttle Position:\t{throttle_position}");*/
-
-            let payload = format!("motor_controller,device=alltrax \
-                battery_voltage={battery_voltage:.1},\
-                motor_current={motor_current:.1},\
-                throttle_pointer={throttle_pointer},\
-                throttle_position={throttle_position},\
-                overtemp_cap={overtemp_cap},\
-                uk_06_07={uk_6_7},\
-                uk_10_11={uk_10_11},\
-                uk_12_13={uk_12_13},\
-                uk_20_21={uk_20_21},\
-                uk_22_23={uk_22_23},\
-                uk_24_25={uk_24_25},\
-                uk_26_27={uk_26_27},\
-                uk_28_29={uk_28_29},\
-                uk_30_31={uk_30_31},\
-                uk_32_33={uk_32_33},\
-                uk_34_35={uk_34_35},\
-                uk_36_37={uk_36_37},\
-                uk_38_39={uk_38_39},\
-                uk_40_41={uk_40_41},\
-                uk_42_43={uk_42_43},\
-                uk_44_45={uk_44_45},\
-                uk_48_49={uk_48_49},\
-                uk_50_51={uk_50_51},\
-                uk_52_53={uk_52_53},\
-                uk_54_55={uk_54_55},\
-                uk_56_57={uk_56_57},\
-                uk_58_59={uk_58_59},\
-                uk_60_61={uk_60_61},\
-                uk_62_63={uk_62_63}\
-                ");
-
-            //let payload = format!("motor_controller,device=alltrax battery_voltage={battery_voltage:.1},motor_current={motor_current:.1},throttle_pointer={throttle_pointer},throttle_position={throttle_position}");
-            println!("{payload}");
+        // the controller disappears when the usb cable is unplugged or the key is turned off
+        if !controller.is_online() {
+            if !controller.connect() {
+                thread::sleep(RECONNECT_RATE);
+                continue;
+            }
+            write_mqtt_retained(&mqtt_client, STATUS_TOPIC, "online"); //live data for dashboard
+
+            let usb = controller.usb_info();
+            publish_device_info(&mqtt_client, &usb);
+            blocks[0] = usb.model.live(); //the live block map of the connected model
+            drive_metrics = DriveMetrics::new(blocks[0]);
+            next_read = blocks.iter().map(|_| Instant::now()).collect(); //read every block straight away
+        }
+
+        for (i, block) in blocks.iter().enumerate() {
+            if Instant::now() < next_read[i] {
+                continue;
+            }
+            next_read[i] = (next_read[i] + periods[i]).max(Instant::now()); //skip missed reads rather than bursting to catch up
+
+            let request = block.request();
+            match controller.transfer(&request, &mut buf) { //write request and wait for the matching response, check length and id, and address and checksum with --strict-frames
+                Ok(response) => {
+                    match &response.layout {
+                        Ok(_) => frame_stats.record(&Ok(())),
+                        Err(e) => {
+                            println!("Used {} frame with a layout mismatch: {e}", block.name);
+                            frame_stats.record_unverified(e);
+                        }
+                    }
+                    frame_stats.stale += response.stale;
+
+                    let payload = block.decode(&buf);
+                    write_mqtt_message(&mqtt_client, "motor_controller", payload.as_str());
+
+                    if block.name == LIVE.name {
+                        publish_flags(&mqtt_client, &mut fault_monitor, block, &buf);
+                        publish_metrics(&mqtt_client, &mut drive_metrics, &buf);
+                    }
+                }
+                Err(TransferError::Frame(e)) => {
+                    println!("Rejected {} frame: {e}", block.name);
+                    frame_stats.record(&Err(e));
+                }
+                Err(TransferError::Timeout) => {
+                    println!("No response to {} read", block.name);
+                    frame_stats.timeouts += 1;
+                }
+                Err(e) => println!("Failed to read {} block: {e}", block.name),
+            }
+
+            if !controller.is_online() {
+                println!("Controller offline");
+                write_mqtt_retained(&mqtt_client, STATUS_TOPIC, "offline"); //live data for dashboard
+                publish_trip(&mqtt_client, drive_metrics.end_trip()); //losing the controller ends the trip
+                break;
+            }
+        }
+
+        if last_stats.elapsed() >= FRAME_STATS_RATE {
+            let payload = format!("motor_controller,device=alltrax {}", frame_stats.fields());
             write_mqtt_message(&mqtt_client, "motor_controller", payload.as_str());
+            write_mqtt_message(&mqtt_client, "live/motor_controller/frames_rejected", format!("{}", frame_stats.rejected).as_str()); //live data for dashboard
+            last_stats = Instant::now();
         }
 
-        thread::sleep(Duration::from_millis(450));
+        // sleep until the next block is due
+        if let Some(next) = next_read.iter().min() {
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+// the live block and the extra blocks asked for
+fn poll_blocks(extra_blocks: &[String]) -> Vec<&'static MemoryBlock> {
+    let mut blocks = vec![&LIVE];
+    blocks.extend(memory::OPTIONAL_BLOCKS.iter().copied().filter(|b| extra_blocks.iter().any(|name| name == b.name)));
+    blocks
+}
+
+// each block's own period, or the requested rate for the live block
+fn poll_periods(blocks: &[&MemoryBlock], live_period: Option<Duration>) -> Vec<Duration> {
+    blocks.iter().map(|b| if b.name == LIVE.name { live_period.unwrap_or(b.period) } else { b.period }).collect()
+}
+
+// retained usb product, model, firmware and serial for the dashboard, and a record of which controller was connected
+fn publish_device_info(mqtt_client: &Client, usb: &UsbInfo) {
+    write_mqtt_message(mqtt_client, "motor_controller", usb.line().as_str());
+    write_mqtt_retained(mqtt_client, "live/motor_controller/product", usb.product.as_deref().unwrap_or("unknown")); //live data for dashboard
+    write_mqtt_retained(mqtt_client, "live/motor_controller/model", usb.model.name()); //live data for dashboard
+    write_mqtt_retained(mqtt_client, "live/motor_controller/firmware", usb.firmware.as_deref().unwrap_or("unknown")); //live data for dashboard
+    write_mqtt_retained(mqtt_client, "live/motor_controller/serial", usb.serial.as_deref().unwrap_or("unknown")); //live data for dashboard
+}
+
+// remove a flag and its value from the arguments
+fn take_flag(args: &mut Vec<String>, flag: &str, value: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == flag)?;
+    let Some(v) = args.get(i + 1).cloned() else {
+        println!("{flag} requires {value}");
+        process::exit(1);
+    };
+    args.drain(i..i + 2);
+
+    Some(v)
+}
+
+// remove a flag without a value from the arguments, true if it was given
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    let Some(i) = args.iter().position(|a| a == flag) else {
+        return false;
+    };
+    args.remove(i);
+
+    true
+}
+
+// publish decoded fault and status flags, raised/cleared events and the retained active fault list
+fn publish_flags(mqtt_client: &Client, fault_monitor: &mut FaultMonitor, live: &MemoryBlock, buf: &[u8; MSG_LEN]) {
+    let (fields, events) = fault_monitor.update(live, buf);
+    write_mqtt_message(mqtt_client, "motor_controller", format!("motor_controller,device=alltrax,block=flags {fields}").as_str());
+
+    for event in events.iter() {
+        let state = if event.raised { "raised" } else { "cleared" };
+        println!("Flag {state}: {}", event.name);
+
+        write_mqtt_message(mqtt_client, "motor_controller", format!("motor_controller_event,device=alltrax,flag={} raised={}", event.name, event.raised as u8).as_str());
+        write_mqtt_message(mqtt_client, "live/motor_controller/fault_event", format!("{state},{}", event.name).as_str()); //live data for dashboard
+    }
+
+    if !events.is_empty() {
+        write_mqtt_retained(mqtt_client, "live/motor_controller/faults", fault_monitor.active_faults().as_str()); //live data for dashboard
+    }
+}
+
+// publish motor power, throttle response and the running trip totals
+fn publish_metrics(mqtt_client: &Client, drive_metrics: &mut DriveMetrics, buf: &[u8; MSG_LEN]) {
+    let derived = drive_metrics.update(buf);
+    publish_trip(mqtt_client, derived.finished_trip);
+
+    let mut fields = format!("motor_power={:.0}", derived.motor_power);
+    if let Some(ms) = derived.throttle_response_ms {
+        fields.push_str(&format!(",throttle_response_ms={ms}"));
+        write_mqtt_message(mqtt_client, "live/motor_controller/throttle_response_ms", format!("{ms}").as_str()); //live data for dashboard
+    }
+    if let Some(totals) = drive_metrics.trip_totals() {
+        fields.push_str(&format!(",{}", totals.fields()));
+        write_mqtt_message(mqtt_client, "live/motor_controller/trip_drive_wh", format!("{:.1}", totals.drive_wh).as_str()); //live data for dashboard
+        write_mqtt_message(mqtt_client, "live/motor_controller/trip_regen_wh", format!("{:.1}", totals.regen_wh).as_str()); //live data for dashboard
+    }
+
+    write_mqtt_message(mqtt_client, "motor_controller", format!("motor_controller,device=alltrax,block=derived {fields}").as_str());
+    write_mqtt_message(mqtt_client, "live/motor_controller/motor_power", format!("{:.0}", derived.motor_power).as_str()); //live data for dashboard
+}
+
+// publish the totals of a finished trip
+fn publish_trip(mqtt_client: &Client, finished_trip: Option<(Duration, Totals)>) {
+    if let Some((duration, totals)) = finished_trip {
+        let payload = format!("motor_controller_trip,device=alltrax duration_s={},{}", duration.as_secs(), totals.fields());
+        write_mqtt_message(mqtt_client, "motor_controller", payload.as_str());
     }
 }
 
 fn write_mqtt_message(mqtt_client: &Client, topic: &str, payload: &str){
     let msg = mqtt::Message::new(topic, payload, 1); //build message
+    publish_mqtt_message(mqtt_client, msg, payload);
+}
+
+// retained messages are kept by the broker and sent to new subscribers
+fn write_mqtt_retained(mqtt_client: &Client, topic: &str, payload: &str){
+    let msg = mqtt::Message::new_retained(topic, payload, 1); //build message
+    publish_mqtt_message(mqtt_client, msg, payload);
+}
+
+fn publish_mqtt_message(mqtt_client: &Client, msg: mqtt::Message, payload: &str){
     if !mqtt_client.is_connected(){ //check if connected to broker
         println!("Lost connection to mqtt broker");
         match mqtt_client.reconnect(){ //reconnext to broker
@@ -127,10 +318,10 @@ fn write_mqtt_message(mqtt_client: &Client, topic: &str, payload: &str){
     }
 }
 
-fn open_mqtt_connection() -> Client {
+fn open_mqtt_connection(client_id: &str) -> Client {
     let create_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(MQTT_IP)
-        .client_id(MQTT_CLIENT_ID.to_string())
+        .client_id(client_id.to_string())
         .finalize();
 
     // Create a client.
@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+use crate::device::{self, Response};
+use crate::transport::Transport;
+use crate::frame::MSG_LEN;
+use crate::{bytes_to_word_signed, bytes_to_word_unsigned};
+
+const REPORT_ID: u8 = 0x01;
+pub const CMD_READ: u8 = 0x2E; // read a block of controller memory
+pub const CMD_WRITE: u8 = 0x2F; // write a block of controller memory, guessed from the read command and not captured yet
+const REQUEST_SUFFIX: [u8; 2] = [0x41, 0x10]; // trailing bytes the Alltrax Toolkit sends with every read
+const READ_DATA_OFFSET: usize = 6; // first data byte in a read response
+const WRITE_DATA_OFFSET: usize = 8; // first data byte in a write request
+pub const CHUNK_LEN: u8 = 0x20; // bytes per read or write request
+
+#[derive(Clone, Copy)]
+pub enum FieldKind {
+    Signed,   // 16 bit little endian
+    Unsigned, // 16 bit little endian
+}
+
+pub struct Field {
+    pub name: &'static str,
+    pub offset: usize, // byte offset in the 64 byte response
+    pub kind: FieldKind,
+    pub scale: f32, // engineering units per raw count
+}
+
+pub struct MemoryBlock {
+    pub name: &'static str,
+    pub tags: &'static str, // influxdb tags for the block's measurement
+    pub address: u16,
+    pub length: u8,
+    pub period: Duration, // how often to read the block
+    pub fields: &'static [Field],
+}
+
+const fn word(name: &'static str, offset: usize) -> Field {
+    Field { name, offset, kind: FieldKind::Signed, scale: 1.0 }
+}
+
+// live data, throttle, current and voltage
+const LIVE_FIELDS: [Field; 29] = [
+    Field { name: "battery_voltage", offset: 8, kind: FieldKind::Unsigned, scale: 0.1 },
+    Field { name: "motor_current", offset: 18, kind: FieldKind::Signed, scale: 0.1 },
+    Field { name: "throttle_pointer", offset: 14, kind: FieldKind::Unsigned, scale: 1.0 },
+    Field { name: "throttle_position", offset: 16, kind: FieldKind::Unsigned, scale: 1.0 },
+    word("overtemp_cap", 46),
+    word("uk_06_07", 6),
+    Field { name: "uk_10_11", offset: 10, kind: FieldKind::Signed, scale: 0.1 },
+    word("uk_12_13", 12),
+    word("uk_20_21", 20),
+    word("uk_22_23", 22),
+    word("uk_24_25", 24),
+    word("uk_26_27", 26),
+    word("uk_28_29", 28),
+    word("uk_30_31", 30),
+    word("uk_32_33", 32),
+    word("uk_34_35", 34),
+    word("uk_36_37", 36),
+    word("uk_38_39", 38),
+    word("uk_40_41", 40),
+    word("uk_42_43", 42),
+    word("uk_44_45", 44),
+    word("uk_48_49", 48),
+    word("uk_50_51", 50),
+    word("uk_52_53", 52),
+    word("uk_54_55", 54),
+    word("uk_56_57", 56),
+    word("uk_58_59", 58),
+    word("uk_60_61", 60),
+    word("uk_62_63", 62),
+];
+
+// raw words of the fault_log and settings blocks, their addresses and layouts are placeholders until a capture confirms them
+const PLACEHOLDER_FIELDS: [Field; 8] = [
+    word("uk_06_07", 6),
+    word("uk_08_09", 8),
+    word("uk_10_11", 10),
+    word("uk_12_13", 12),
+    word("uk_14_15", 14),
+    word("uk_16_17", 16),
+    word("uk_18_19", 18),
+    word("uk_20_21", 20),
+];
+
+pub const LIVE: MemoryBlock = MemoryBlock {
+    name: "live",
+    tags: "device=alltrax",
+    address: 0xA000,
+    length: 0x20,
+    period: Duration::from_millis(500),
+    fields: &LIVE_FIELDS,
+};
+
+pub const FAULT_LOG: MemoryBlock = MemoryBlock {
+    name: "fault_log",
+    tags: "device=alltrax,block=fault_log",
+    address: 0xA100,
+    length: 0x20,
+    period: Duration::from_secs(10),
+    fields: &PLACEHOLDER_FIELDS,
+};
+
+pub const SETTINGS: MemoryBlock = MemoryBlock {
+    name: "settings",
+    tags: "device=alltrax,block=settings",
+    address: 0x0800,
+    length: 0x20,
+    period: Duration::from_secs(60),
+    fields: &PLACEHOLDER_FIELDS,
+};
+
+// placeholder addresses that have not been confirmed with a capture, only polled when asked for with --blocks
+pub const OPTIONAL_BLOCKS: [&MemoryBlock; 2] = [&FAULT_LOG, &SETTINGS];
+
+pub fn build_read_request(address: u16, length: u8) -> [u8; MSG_LEN] {
+    let mut request = [0u8; MSG_LEN];
+
+    request[0] = REPORT_ID;
+    request[1] = CMD_READ;
+    request[2..4].copy_from_slice(&address.to_be_bytes());
+    request[4] = length;
+    request[6..8].copy_from_slice(&REQUEST_SUFFIX);
+
+    request
+}
+
+pub fn build_write_request(address: u16, data: &[u8]) -> [u8; MSG_LEN] {
+    let mut request = [0u8; MSG_LEN];
+
+    request[0] = REPORT_ID;
+    request[1] = CMD_WRITE;
+    request[2..4].copy_from_slice(&address.to_be_bytes());
+    request[4] = data.len() as u8;
+    request[WRITE_DATA_OFFSET..WRITE_DATA_OFFSET + data.len()].copy_from_slice(data);
+
+    request
+}
+
+// read a range of controller memory in chunks, a chunk failing the address or checksum check fails the read
+pub fn read_memory(device: &mut dyn Transport, address: u16, length: usize) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(length);
+    let mut buf = [0u8; MSG_LEN];
+
+    while data.len() < length {
+        let chunk_address = address + data.len() as u16;
+        let chunk_len = (length - data.len()).min(CHUNK_LEN as usize);
+
+        device::transfer(device, &build_read_request(chunk_address, chunk_len as u8), &mut buf)
+            .and_then(Response::strict)
+            .map_err(|e| format!("Reading {chunk_address:#06x}: {e}"))?;
+        data.extend_from_slice(&buf[READ_DATA_OFFSET..READ_DATA_OFFSET + chunk_len]);
+    }
+
+    Ok(data)
+}
+
+// write a range of controller memory in chunks
+pub fn write_memory(device: &mut dyn Transport, address: u16, data: &[u8]) -> Result<(), String> {
+    let mut buf = [0u8; MSG_LEN];
+
+    for (i, chunk) in data.chunks(CHUNK_LEN as usize).enumerate() {
+        let chunk_address = address + (i * CHUNK_LEN as usize) as u16;
+        device::transfer(device, &build_write_request(chunk_address, chunk), &mut buf)
+            .and_then(Response::strict)
+            .map_err(|e| format!("Writing {chunk_address:#06x}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+impl MemoryBlock {
+    pub fn request(&self) -> [u8; MSG_LEN] {
+        build_read_request(self.address, self.length)
+    }
+
+    pub fn field(&self, name: &str) -> Option<&'static Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    // influxdb line protocol for the block
+    pub fn decode(&self, buf: &[u8; MSG_LEN]) -> String {
+        let fields: Vec<String> = self.fields.iter().map(|f| format!("{}={}", f.name, f.format(buf))).collect();
+
+        format!("motor_controller,{} {}", self.tags, fields.join(","))
+    }
+}
+
+impl Field {
+    pub fn raw(&self, buf: &[u8; MSG_LEN]) -> i32 {
+        match self.kind {
+            FieldKind::Signed => bytes_to_word_signed(buf[self.offset], buf[self.offset + 1]) as i32,
+            FieldKind::Unsigned => bytes_to_word_unsigned(buf[self.offset], buf[self.offset + 1]) as i32,
+        }
+    }
+
+    pub fn value(&self, buf: &[u8; MSG_LEN]) -> f32 {
+        self.raw(buf) as f32 * self.scale
+    }
+
+    // encode a value in engineering units, used to build simulated responses
+    pub fn set(&self, buf: &mut [u8; MSG_LEN], value: f32) {
+        let raw = (value / self.scale).round();
+        let bytes = match self.kind {
+            FieldKind::Signed => (raw as i16).to_le_bytes(),
+            FieldKind::Unsigned => (raw as u16).to_le_bytes(),
+        };
+        buf[self.offset..self.offset + 2].copy_from_slice(&bytes);
+    }
+
+    // integers stay integers, scaled values keep the resolution of the scale
+    fn format(&self, buf: &[u8; MSG_LEN]) -> String {
+        if self.scale == 1.0 {
+            format!("{}", self.raw(buf))
+        } else {
+            let decimals = (-self.scale.log10()).round().max(0.0) as usize;
+            format!("{:.*}", decimals, self.value(buf))
+        }
+    }
+}
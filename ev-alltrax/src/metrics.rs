@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use crate::frame::MSG_LEN;
+use crate::memory::{Field, MemoryBlock};
+
+const MAX_STEP: Duration = Duration::from_secs(2); // longer gaps between reads are not integrated
+const RESPONSE_CURRENT: f32 = 10.0; // motor current that counts as the controller responding to throttle
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2); // tip-ins that never reach the response current are dropped
+const ACTIVE_CURRENT: f32 = 5.0; // motor current that counts as driving, either direction
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300); // a trip ends after this long without throttle or current
+const MIN_TRIP: Duration = Duration::from_secs(60); // shorter drives are not reported as trips
+
+// values derived from one live read
+pub struct Derived {
+    pub motor_power: f32, // battery voltage times motor current, not the power drawn from the pack
+    pub throttle_response_ms: Option<u128>, // set on the read that completes a tip-in
+    pub finished_trip: Option<(Duration, Totals)>, // set on the read that ends a trip by going idle
+}
+
+// motor energy and peaks over a drive
+#[derive(Default)]
+pub struct Totals {
+    pub drive_wh: f64, // motor power integrated while driving
+    pub regen_wh: f64, // motor power integrated while regenerating, positive
+    pub peak_current: f32,
+    pub peak_regen_current: f32,
+    pub peak_motor_power: f32,
+}
+
+struct Trip {
+    start: Instant,
+    last_active: Instant, // last read with throttle or current
+    totals: Totals,
+}
+
+pub struct DriveMetrics {
+    battery_voltage: &'static Field,
+    motor_current: &'static Field,
+    throttle_position: &'static Field,
+    last_read: Option<Instant>,
+    last_throttle: i32,
+    tip_in: Option<Instant>, // throttle applied from released, waiting for current
+    trip: Option<Trip>,
+}
+
+impl Totals {
+    // influxdb line protocol fields
+    pub fn fields(&self) -> String {
+        format!(
+            "drive_wh={:.1},regen_wh={:.1},net_wh={:.1},peak_current={:.1},peak_regen_current={:.1},peak_motor_power={:.0}",
+            self.drive_wh,
+            self.regen_wh,
+            self.drive_wh - self.regen_wh,
+            self.peak_current,
+            self.peak_regen_current,
+            self.peak_motor_power
+        )
+    }
+}
+
+impl Trip {
+    // length up to the last activity, so the idle time that ended it isn't counted
+    fn finish(self) -> Option<(Duration, Totals)> {
+        let duration = self.last_active.duration_since(self.start);
+        (duration >= MIN_TRIP).then_some((duration, self.totals))
+    }
+}
+
+impl DriveMetrics {
+    // the live block has these fields
+    pub fn new(live: &'static MemoryBlock) -> DriveMetrics {
+        let field = |name| live.field(name).unwrap_or_else(|| panic!("{name} missing from the {} block", live.name));
+
+        DriveMetrics {
+            battery_voltage: field("battery_voltage"),
+            motor_current: field("motor_current"),
+            throttle_position: field("throttle_position"),
+            last_read: None,
+            last_throttle: 0,
+            tip_in: None,
+            trip: None,
+        }
+    }
+
+    // derive power and response from a live read, start or end a trip and add the read to its totals
+    pub fn update(&mut self, buf: &[u8; MSG_LEN]) -> Derived {
+        let now = Instant::now();
+        let voltage = self.battery_voltage.value(buf);
+        let current = self.motor_current.value(buf); // negative while regenerating
+        let throttle = self.throttle_position.raw(buf);
+
+        // motor current is larger than the battery current by 1/duty at part throttle, so this overstates pack power
+        // and the energy below is motor side energy, ev-mcu measures pack energy from the pack current
+        let motor_power = voltage * current;
+
+        // a trip starts with throttle or current and ends after a while with neither, the key switch isn't mapped yet
+        let active = throttle > 0 || current.abs() >= ACTIVE_CURRENT;
+        let mut finished_trip = None;
+        match self.trip.as_mut() {
+            Some(trip) if active => trip.last_active = now,
+            Some(trip) if now.duration_since(trip.last_active) > IDLE_TIMEOUT => finished_trip = self.trip.take().and_then(Trip::finish),
+            None if active => self.trip = Some(Trip { start: now, last_active: now, totals: Totals::default() }),
+            _ => (),
+        }
+
+        if let Some(trip) = self.trip.as_mut() {
+            if let Some(dt) = self.last_read.map(|last| now.duration_since(last)).filter(|dt| *dt <= MAX_STEP) {
+                let wh = motor_power as f64 * dt.as_secs_f64() / 3600.0;
+                if wh >= 0.0 {
+                    trip.totals.drive_wh += wh;
+                } else {
+                    trip.totals.regen_wh -= wh;
+                }
+            }
+
+            trip.totals.peak_current = trip.totals.peak_current.max(current);
+            trip.totals.peak_regen_current = trip.totals.peak_regen_current.max(-current);
+            trip.totals.peak_motor_power = trip.totals.peak_motor_power.max(motor_power);
+        }
+        self.last_read = Some(now);
+
+        // time from pressing the throttle to current flowing
+        if self.last_throttle == 0 && throttle > 0 {
+            self.tip_in = Some(now);
+        }
+        if throttle == 0 {
+            self.tip_in = None;
+        }
+        self.last_throttle = throttle;
+
+        let mut throttle_response_ms = None;
+        if let Some(start) = self.tip_in {
+            if current >= RESPONSE_CURRENT {
+                throttle_response_ms = Some(now.duration_since(start).as_millis());
+                self.tip_in = None;
+            } else if now.duration_since(start) > RESPONSE_TIMEOUT {
+                self.tip_in = None;
+            }
+        }
+
+        Derived { motor_power, throttle_response_ms, finished_trip }
+    }
+
+    // the controller went offline, key off or unplugged, returns the totals of a finished trip and its length
+    pub fn end_trip(&mut self) -> Option<(Duration, Totals)> {
+        self.last_read = None;
+        self.trip.take().and_then(Trip::finish)
+    }
+
+    pub fn trip_totals(&self) -> Option<&Totals> {
+        self.trip.as_ref().map(|t| &t.totals)
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs;
+
+use crate::device;
+use crate::memory::{self, CHUNK_LEN, SETTINGS};
+use crate::transport::Transport;
+
+const PROFILE_VERSION: u32 = 1; // bump when the file layout changes
+const SETTINGS_LENGTH: usize = 0x100; // configuration memory starting at the settings block
+const BYTES_PER_LINE: usize = 16;
+pub const UNCONFIRMED_FLAG: &str = "--unconfirmed"; // required to write with the guessed command
+
+// controller configuration memory, as read from the controller or a profile file
+// the address and the write command are guesses, writing needs --unconfirmed until the Alltrax Toolkit is captured
+pub struct Profile {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+pub struct Change {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl Profile {
+    // versioned text file, a header then 16 bytes of hex per line
+    pub fn format(&self) -> String {
+        let mut text = String::from("# Alltrax controller settings\n");
+        text.push_str(&format!("version={PROFILE_VERSION}\n"));
+        text.push_str(&format!("address={:#06x}\n", self.address));
+        text.push_str(&format!("length={:#06x}\n", self.data.len()));
+
+        for (i, line) in self.data.chunks(BYTES_PER_LINE).enumerate() {
+            let bytes: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+            text.push_str(&format!("{:04x}: {}\n", self.address as usize + i * BYTES_PER_LINE, bytes.join(" ")));
+        }
+
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let mut version = None;
+        let mut address = None;
+        let mut length = None;
+        let mut data = Vec::new();
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid line {}: {line}", line_number + 1);
+
+            if let Some((key, value)) = line.split_once('=') {
+                match key {
+                    "version" => version = Some(value.parse::<u32>().map_err(|_| error())?),
+                    "address" => address = Some(parse_hex(value).ok_or_else(error)?),
+                    "length" => length = Some(parse_hex(value).ok_or_else(error)? as usize),
+                    _ => return Err(error()),
+                }
+                continue;
+            }
+
+            // data line, check the address so missing or reordered lines are caught
+            let (line_address, bytes) = line.split_once(':').ok_or_else(error)?;
+            let line_address = parse_hex(line_address).ok_or_else(error)?;
+            if Some(line_address as usize) != address.map(|a| a as usize + data.len()) {
+                return Err(format!("Unexpected address on line {}: {line_address:#06x}", line_number + 1));
+            }
+
+            for byte in bytes.split_whitespace() {
+                data.push(u8::from_str_radix(byte, 16).map_err(|_| error())?);
+            }
+        }
+
+        match version {
+            Some(PROFILE_VERSION) => (),
+            Some(v) => return Err(format!("Unsupported profile version {v}, expected {PROFILE_VERSION}")),
+            None => return Err("Missing profile version".to_string()),
+        }
+
+        let address = address.ok_or("Missing profile address")?;
+        if Some(data.len()) != length {
+            return Err(format!("Profile has {} bytes, header says {:?}", data.len(), length));
+        }
+
+        Ok(Profile { address, data })
+    }
+
+    pub fn load(path: &str) -> Result<Profile, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        Profile::parse(&text).map_err(|e| format!("{path}: {e}"))
+    }
+
+    pub fn read(device: &mut dyn Transport) -> Result<Profile, String> {
+        let data = memory::read_memory(device, SETTINGS.address, SETTINGS_LENGTH)?;
+        Ok(Profile { address: SETTINGS.address, data })
+    }
+
+    // bytes that differ between self (the controller) and another profile
+    pub fn diff(&self, other: &Profile) -> Result<Vec<Change>, String> {
+        if self.address != other.address || self.data.len() != other.data.len() {
+            return Err(format!(
+                "Profile covers {:#06x}+{:#x}, controller settings are {:#06x}+{:#x}",
+                other.address,
+                other.data.len(),
+                self.address,
+                self.data.len()
+            ));
+        }
+
+        let changes = self
+            .data
+            .iter()
+            .zip(other.data.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (old, new))| Change { address: self.address + i as u16, old: *old, new: *new })
+            .collect();
+
+        Ok(changes)
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u16> {
+    let value = value.trim();
+    u16::from_str_radix(value.strip_prefix("0x").unwrap_or(value), 16).ok()
+}
+
+fn print_changes(changes: &[Change]) {
+    if changes.is_empty() {
+        println!("No differences");
+        return;
+    }
+
+    println!("{:<8} {:>10} {:>8}", "address", "controller", "profile");
+    for change in changes.iter() {
+        println!("{:#06x}   {:>10} {:>8}", change.address, format!("{:02x}", change.old), format!("{:02x}", change.new));
+    }
+    println!("{} bytes differ", changes.len());
+}
+
+// save the controller settings to a profile file
+pub fn dump(path: &str) -> Result<(), String> {
+    let profile = Profile::read(&mut device::open()?)?;
+    fs::write(path, profile.format()).map_err(|e| format!("Failed to write {path}: {e}"))?;
+
+    println!("Saved {} bytes from {:#06x} to {path}", profile.data.len(), profile.address);
+    Ok(())
+}
+
+// compare the controller settings with a profile file
+pub fn diff(path: &str) -> Result<(), String> {
+    let profile = Profile::load(path)?;
+    let current = Profile::read(&mut device::open()?)?;
+
+    print_changes(&current.diff(&profile)?);
+    Ok(())
+}
+
+// write the chunks that differ from a profile file, then read back and verify
+pub fn write(path: &str, confirmed: bool) -> Result<(), String> {
+    let profile = Profile::load(path)?;
+    let mut controller = device::open()?;
+    let current = Profile::read(&mut controller)?;
+
+    let changes = current.diff(&profile)?;
+    print_changes(&changes);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    if !confirmed {
+        println!("Run again with --yes to write these changes");
+        return Ok(());
+    }
+
+    for (i, chunk) in profile.data.chunks(CHUNK_LEN as usize).enumerate() {
+        let offset = i * CHUNK_LEN as usize;
+        if chunk == &current.data[offset..offset + chunk.len()] {
+            continue;
+        }
+        memory::write_memory(&mut controller, profile.address + offset as u16, chunk)?;
+    }
+
+    let written = Profile::read(&mut controller)?;
+    let mismatches = written.diff(&profile)?;
+    if !mismatches.is_empty() {
+        print_changes(&mismatches);
+        return Err("Verification failed, controller settings do not match the profile".to_string());
+    }
+
+    println!("Wrote {} bytes, verified", changes.len());
+    Ok(())
+}
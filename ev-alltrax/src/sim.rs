@@ -0,0 +1,306 @@
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::faults::FlagMap;
+use crate::frame::{self, MSG_LEN};
+use crate::memory::{Field, LIVE};
+use crate::transport::Transport;
+
+const THROTTLE_FULL: f32 = 255.0; // throttle position at full throttle, also reported as overtemp_cap
+const MAX_CURRENT: f32 = 300.0; // motor current at full throttle
+const REGEN_CURRENT: f32 = 50.0; // motor current while braking
+const CURRENT_LAG: f32 = 0.2; // time constant in seconds of the current following the throttle
+const PACK_RESISTANCE: f32 = 0.02; // ohms, sags the battery voltage under load
+const DEFAULT_VOLTAGE: f32 = 52.0;
+
+enum Command {
+    Throttle { target: f32, ramp: f32 }, // ramp time in seconds, 0 for a step
+    Voltage(f32),
+    Flag { field: &'static str, mask: u16, set: bool },
+    Key { on: bool, flag: Option<(&'static str, u16)> }, // also sets the key_on bit when the flag map has one
+    Stall(f32), // no responses for this many seconds
+    Unplug,     // usb error, the service reconnects and the script starts again
+    End,        // script length, it repeats from the start
+}
+
+struct Step {
+    time: f32,
+    command: Command,
+}
+
+// timed steps driving the simulated controller, see scripts/drive_cycle.sim
+pub struct Script {
+    steps: Vec<Step>,
+    length: f32,
+}
+
+// the simulated controller at a point in the script
+struct State {
+    throttle: f32,
+    voltage: f32,
+    words: Vec<(&'static str, u16)>, // flag words by live block field
+    key_on: bool, // on until the script turns it off, no current while off
+    stalled: bool,
+    unplugged: bool,
+}
+
+// answers reads with responses generated from a script, current follows throttle with a lag
+pub struct SimTransport {
+    script: Script,
+    flags: FlagMap,
+    start: Instant,
+    request: Option<[u8; MSG_LEN]>, // written and not yet answered
+    current: f32,
+    last_read: Instant,
+}
+
+impl Script {
+    pub fn load(path: &str, flags: &FlagMap) -> Result<Script, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        Script::parse(&text, flags).map_err(|e| format!("{path}: {e}"))
+    }
+
+    fn parse(text: &str, flags: &FlagMap) -> Result<Script, String> {
+        let mut steps = Vec::new();
+
+        for (line_number, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid line {}: {line}", line_number + 1);
+            let number = |i: usize| words.get(i).and_then(|w| w.parse::<f32>().ok()).ok_or_else(error);
+            let flag = |name: &str, set: bool| {
+                let flag = flags.find(name).ok_or_else(|| format!("Flag {name} on line {} is not in the flag map", line_number + 1))?;
+                Ok::<Command, String>(Command::Flag { field: flag.field, mask: flag.mask, set })
+            };
+
+            let time = number(0)?;
+            let command = match (words.get(1).copied(), words.get(2).copied()) {
+                (Some("throttle"), _) => Command::Throttle { target: number(2)?, ramp: number(3).unwrap_or(0.0) },
+                (Some("voltage"), _) => Command::Voltage(number(2)?),
+                (Some("flag"), Some(name)) => flag(name, true)?,
+                (Some("clear"), Some(name)) => flag(name, false)?,
+                (Some("key"), Some(state @ ("on" | "off"))) => {
+                    Command::Key { on: state == "on", flag: flags.find("key_on").map(|f| (f.field, f.mask)) }
+                }
+                (Some("stall"), _) => Command::Stall(number(2)?),
+                (Some("unplug"), _) => Command::Unplug,
+                (Some("end"), _) => Command::End,
+                _ => return Err(error()),
+            };
+
+            if steps.last().is_some_and(|s: &Step| s.time > time) {
+                return Err(format!("Steps out of order on line {}", line_number + 1));
+            }
+            steps.push(Step { time, command });
+        }
+
+        let length = steps
+            .iter()
+            .find(|s| matches!(s.command, Command::End))
+            .or(steps.last())
+            .map_or(1.0, |s| s.time.max(1.0));
+
+        Ok(Script { steps, length })
+    }
+
+    fn state_at(&self, t: f32) -> State {
+        let mut state = State { throttle: 0.0, voltage: DEFAULT_VOLTAGE, words: Vec::new(), key_on: true, stalled: false, unplugged: false };
+        let mut ramp: Option<(f32, f32, f32, f32)> = None; // start time, from, to, duration
+
+        // throttle on a ramp at a time
+        let ramp_value = |(start, from, to, duration): (f32, f32, f32, f32), at: f32| {
+            let progress = if duration > 0.0 { ((at - start) / duration).clamp(0.0, 1.0) } else { 1.0 };
+            from + (to - from) * progress
+        };
+
+        for step in self.steps.iter().take_while(|s| s.time <= t) {
+            match step.command {
+                Command::Throttle { target, ramp: duration } => {
+                    let from = ramp.map_or(0.0, |r| ramp_value(r, step.time));
+                    ramp = Some((step.time, from, target, duration));
+                }
+                Command::Voltage(voltage) => state.voltage = voltage,
+                Command::Flag { field, mask, set } => state.set_bits(field, mask, set),
+                Command::Key { on, flag } => {
+                    state.key_on = on;
+                    if let Some((field, mask)) = flag {
+                        state.set_bits(field, mask, on);
+                    }
+                }
+                Command::Stall(duration) => state.stalled = t < step.time + duration,
+                Command::Unplug => state.unplugged = true,
+                Command::End => (),
+            }
+        }
+
+        state.throttle = ramp.map_or(0.0, |r| ramp_value(r, t));
+        state
+    }
+}
+
+impl State {
+    fn set_bits(&mut self, field: &'static str, mask: u16, set: bool) {
+        let i = self.words.iter().position(|(f, _)| *f == field).unwrap_or_else(|| {
+            self.words.push((field, 0));
+            self.words.len() - 1
+        });
+        if set {
+            self.words[i].1 |= mask;
+        } else {
+            self.words[i].1 &= !mask;
+        }
+    }
+
+    // a flag missing from the map is never set
+    fn flag(&self, flags: &FlagMap, name: &str) -> bool {
+        let Some(flag) = flags.find(name) else { return false };
+        self.words.iter().any(|(f, value)| *f == flag.field && value & flag.mask == flag.mask)
+    }
+}
+
+impl SimTransport {
+    pub fn new(script: Script, flags: FlagMap) -> SimTransport {
+        println!("Simulating controller, script repeats every {}s", script.length);
+        SimTransport { script, flags, start: Instant::now(), request: None, current: 0.0, last_read: Instant::now() }
+    }
+
+    fn fill_live(&mut self, state: &State, buf: &mut [u8; MSG_LEN]) {
+        let target = if !state.key_on {
+            0.0
+        } else if state.flag(&self.flags, "brake") {
+            -REGEN_CURRENT
+        } else if state.flag(&self.flags, "overtemp_cutback") {
+            state.throttle / THROTTLE_FULL * MAX_CURRENT / 2.0
+        } else {
+            state.throttle / THROTTLE_FULL * MAX_CURRENT
+        };
+
+        let dt = self.last_read.elapsed().as_secs_f32();
+        self.current += (target - self.current) * (1.0 - (-dt / CURRENT_LAG).exp());
+        self.last_read = Instant::now();
+
+        let set = |buf: &mut [u8; MSG_LEN], name: &str, value: f32| {
+            if let Some(field) = LIVE.field(name) {
+                field.set(buf, value);
+            }
+        };
+        set(buf, "battery_voltage", state.voltage - self.current * PACK_RESISTANCE);
+        set(buf, "motor_current", self.current);
+        set(buf, "throttle_pointer", state.throttle);
+        set(buf, "throttle_position", state.throttle);
+        set(buf, "overtemp_cap", THROTTLE_FULL);
+
+        // flag words are bit fields, write them raw
+        for (name, value) in state.words.iter() {
+            if let Some(field) = LIVE.field(name) {
+                write_word(field, buf, *value);
+            }
+        }
+    }
+}
+
+fn write_word(field: &Field, buf: &mut [u8; MSG_LEN], value: u16) {
+    buf[field.offset..field.offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+impl Transport for SimTransport {
+    fn write(&mut self, request: &[u8; MSG_LEN]) -> Result<(), String> {
+        self.request = Some(*request);
+        Ok(())
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8; MSG_LEN], timeout: Duration) -> Result<usize, String> {
+        let t = self.start.elapsed().as_secs_f32() % self.script.length;
+        let state = self.script.state_at(t);
+
+        if state.unplugged {
+            return Err("simulated unplug".to_string());
+        }
+
+        let Some(request) = self.request.take().filter(|_| !state.stalled) else {
+            thread::sleep(timeout);
+            return Ok(0);
+        };
+
+        // only the live block is simulated, other blocks read as zeros
+        *buf = [0u8; MSG_LEN];
+        if request == LIVE.request() {
+            self.fill_live(&state, buf);
+        }
+        frame::seal_response(&request, buf);
+
+        Ok(MSG_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+    use crate::faults::FaultMonitor;
+
+    const FLAGS: &str = "overtemp = fault uk_44_45 0x0001\nkey_on = status uk_42_43 0x0001\n";
+    const SCRIPT: &str = "0 key on\n0 flag overtemp\n1 clear overtemp\n2 end\n";
+
+    // read the live block at a point in the script
+    fn read_at(sim: &mut SimTransport, t: f32, buf: &mut [u8; MSG_LEN]) {
+        sim.start = Instant::now() - Duration::from_secs_f32(t);
+        device::transfer(sim, &LIVE.request(), buf).ok().unwrap();
+    }
+
+    #[test]
+    fn scripted_fault_raises_and_clears() {
+        let flags = FlagMap::parse(FLAGS, &LIVE).unwrap();
+        let script = Script::parse(SCRIPT, &flags).unwrap();
+        let mut sim = SimTransport::new(script, flags.clone());
+        let mut monitor = FaultMonitor::new(flags);
+        let mut buf = [0u8; MSG_LEN];
+
+        read_at(&mut sim, 0.5, &mut buf);
+        let (fields, events) = monitor.update(&LIVE, &buf);
+        assert!(fields.contains("overtemp=1"));
+        assert!(events.iter().any(|e| e.name == "overtemp" && e.raised));
+        assert!(fields.contains("key_on=1"));
+        assert_eq!(monitor.active_faults(), "overtemp");
+
+        // still set, no new event
+        read_at(&mut sim, 0.6, &mut buf);
+        let (_, events) = monitor.update(&LIVE, &buf);
+        assert!(events.is_empty());
+
+        read_at(&mut sim, 1.5, &mut buf);
+        let (fields, events) = monitor.update(&LIVE, &buf);
+        assert!(fields.contains("overtemp=0"));
+        assert!(events.iter().any(|e| e.name == "overtemp" && !e.raised));
+        assert_eq!(monitor.active_faults(), "none");
+    }
+
+    #[test]
+    fn key_off_without_key_on_flag_stops_current() {
+        let flags = FlagMap::parse("overtemp = fault uk_44_45 0x0001\n", &LIVE).unwrap();
+        let script = Script::parse("0 key on\n0 throttle 255\n1 key off\n2 end\n", &flags).unwrap();
+        let mut sim = SimTransport::new(script, flags);
+        let mut buf = [0u8; MSG_LEN];
+        let current = LIVE.field("motor_current").unwrap();
+
+        // long enough since the last read for the current to settle
+        sim.last_read = Instant::now() - Duration::from_secs(10);
+        read_at(&mut sim, 0.5, &mut buf);
+        assert_eq!(current.value(&buf).round(), MAX_CURRENT);
+
+        sim.last_read = Instant::now() - Duration::from_secs(10);
+        read_at(&mut sim, 1.5, &mut buf);
+        assert_eq!(current.value(&buf).round(), 0.0);
+        assert_eq!(LIVE.field("uk_42_43").unwrap().raw(&buf), 0); // no key_on bit without a mapping
+    }
+
+    #[test]
+    fn script_rejects_unmapped_flag() {
+        let flags = FlagMap::parse(FLAGS, &LIVE).unwrap();
+        assert!(Script::parse("0 flag brake\n", &flags).is_err());
+        assert!(Script::parse("1 throttle 100\n0 end\n", &flags).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::thread;
+use std::time::Duration;
+
+use hidapi::HidDevice;
+
+use crate::frame::{self, MSG_LEN};
+use crate::memory::LIVE;
+use crate::workbench;
+
+// request/response link to the controller, usb or a stand in for testing without hardware
+pub trait Transport {
+    fn write(&mut self, request: &[u8; MSG_LEN]) -> Result<(), String>;
+
+    // bytes read, 0 when no response arrived before the timeout
+    fn read_timeout(&mut self, buf: &mut [u8; MSG_LEN], timeout: Duration) -> Result<usize, String>;
+}
+
+impl Transport for HidDevice {
+    fn write(&mut self, request: &[u8; MSG_LEN]) -> Result<(), String> {
+        HidDevice::write(self, request).map(|_| ()).map_err(|e| format!("{e}"))
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8; MSG_LEN], timeout: Duration) -> Result<usize, String> {
+        HidDevice::read_timeout(self, &mut buf[..], timeout.as_millis() as i32).map_err(|e| format!("{e}"))
+    }
+}
+
+// answers live block reads with responses recorded by `ev-alltrax capture`, looping at the end
+pub struct ReplayTransport {
+    frames: Vec<[u8; MSG_LEN]>,
+    next: usize,
+    request: Option<[u8; MSG_LEN]>, // written and not yet answered
+}
+
+impl ReplayTransport {
+    pub fn load(path: &str) -> Result<ReplayTransport, String> {
+        let frames = workbench::load_frames(path)?;
+        if frames.is_empty() {
+            return Err(format!("No frames in {path}"));
+        }
+
+        println!("Replaying {} frames from {path}", frames.len());
+        Ok(ReplayTransport { frames, next: 0, request: None })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write(&mut self, request: &[u8; MSG_LEN]) -> Result<(), String> {
+        self.request = Some(*request);
+        Ok(())
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8; MSG_LEN], timeout: Duration) -> Result<usize, String> {
+        let Some(request) = self.request.take() else {
+            thread::sleep(timeout);
+            return Ok(0);
+        };
+
+        // captures only hold the live block, other blocks read as zeros
+        if request == LIVE.request() {
+            *buf = self.frames[self.next];
+            self.next = (self.next + 1) % self.frames.len();
+        } else {
+            *buf = [0u8; MSG_LEN];
+        }
+        frame::seal_response(&request, buf);
+
+        Ok(MSG_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+
+    // one live block response from a capture: 52.0V, throttle 100, 123.4A, raw flag word 0x0001
+    const CAPTURE: &str = "timestamp_ms,throttle_position,motor_current,gps_speed,raw
+1700000000000,100,123.4,NaN,012e0000a000000008020000000064006400d2040000000000000000000000000000000000000000000001000000ff0000000000000000000000000000000000
+";
+
+    fn write_capture(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ev-alltrax-{name}-{}.csv", std::process::id()));
+        std::fs::write(&path, CAPTURE).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn replay_decodes_captured_frame() {
+        let path = write_capture("replay");
+        let mut replay = ReplayTransport::load(&path).unwrap();
+        let mut buf = [0u8; MSG_LEN];
+
+        let response = device::transfer(&mut replay, &LIVE.request(), &mut buf).ok().unwrap();
+        assert_eq!(response.stale, 0);
+        assert!(response.layout.is_ok());
+
+        let value = |name| LIVE.field(name).unwrap().value(&buf);
+        assert_eq!(value("battery_voltage"), 52.0);
+        assert_eq!(value("throttle_position"), 100.0);
+        assert!((value("motor_current") - 123.4).abs() < 0.01);
+        assert_eq!(value("overtemp_cap"), 255.0);
+
+        let line = LIVE.decode(&buf);
+        assert!(line.starts_with("motor_controller,device=alltrax battery_voltage=52.0,motor_current=123.4,throttle_pointer=100,"));
+        assert!(line.contains(",uk_42_43=1,"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_loops_and_zeros_other_blocks() {
+        let path = write_capture("loop");
+        let mut replay = ReplayTransport::load(&path).unwrap();
+        let mut buf = [0u8; MSG_LEN];
+
+        let other = crate::memory::build_read_request(0xA100, 0x20);
+        device::transfer(&mut replay, &other, &mut buf).ok().unwrap();
+        assert_eq!(LIVE.field("battery_voltage").unwrap().value(&buf), 0.0);
+
+        for _ in 0..2 {
+            device::transfer(&mut replay, &LIVE.request(), &mut buf).ok().unwrap();
+            assert_eq!(LIVE.field("battery_voltage").unwrap().value(&buf), 52.0);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::device::{self, TransferError};
+use crate::faults::FLAG_WORDS;
+use crate::frame::{self, FrameStats, MSG_LEN};
+use crate::memory::LIVE;
+use crate::{bytes_to_word_signed, bytes_to_word_unsigned, open_mqtt_connection};
+
+const MQTT_CLIENT_ID: &str = "alltrax-capture";
+const GPS_SPEED_TOPIC: &str = "live/gps/speed";
+const CAPTURE_RATE: Duration = Duration::from_millis(100); // time between requests while capturing
+const CAPTURE_HEADER: &str = "timestamp_ms,throttle_position,motor_current,gps_speed,raw";
+
+const KNOWN_SIGNALS: [&str; 3] = ["throttle_position", "motor_current", "gps_speed"];
+const MIN_CORRELATION: f64 = 0.5; // weaker correlations are not reported as candidates
+
+struct Sample {
+    raw: [u8; MSG_LEN],
+    known: [f64; 3], // throttle_position, motor_current, gps_speed (NaN when no gps)
+}
+
+// log every valid response with the known signals until stopped
+pub fn capture(path: &str) -> Result<(), String> {
+    let mut device = device::open()?;
+
+    let mqtt_client = open_mqtt_connection(MQTT_CLIENT_ID);
+    let rx = mqtt_client.start_consuming();
+    if let Err(e) = mqtt_client.subscribe(GPS_SPEED_TOPIC, 0) {
+        println!("Error subscribing to {GPS_SPEED_TOPIC}, capturing without gps speed: {:?}", e);
+    }
+
+    let mut file = BufWriter::new(File::create(path).map_err(|e| format!("Failed to create {path}: {e}"))?);
+    writeln!(file, "{CAPTURE_HEADER}").map_err(|e| format!("{e}"))?;
+
+    let request = LIVE.request();
+    let throttle_position = LIVE.field("throttle_position").unwrap();
+    let motor_current = LIVE.field("motor_current").unwrap();
+
+    let mut buf = [0u8; MSG_LEN];
+    let mut gps_speed = f64::NAN;
+    let mut frame_stats = FrameStats::default();
+
+    println!("Capturing to {path}, press Ctrl+C to stop");
+    loop {
+        for msg in rx.try_iter().flatten() {
+            gps_speed = msg.payload_str().parse().unwrap_or(f64::NAN);
+        }
+
+        let accepted = match device::transfer(&mut device, &request, &mut buf) {
+            // kept with a layout mismatch, captures are how the address echo and checksum get confirmed
+            Ok(response) => {
+                frame_stats.stale += response.stale;
+                match &response.layout {
+                    Ok(_) => frame_stats.record(&Ok(())),
+                    Err(e) => frame_stats.record_unverified(e),
+                }
+                true
+            }
+            Err(TransferError::Frame(e)) => {
+                frame_stats.record(&Err(e));
+                false
+            }
+            Err(TransferError::Timeout) => {
+                frame_stats.timeouts += 1;
+                false
+            }
+            Err(e) => return Err(format!("{e}")),
+        };
+
+        if accepted {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            let raw: String = buf.iter().map(|b| format!("{b:02x}")).collect();
+
+            writeln!(file, "{timestamp},{},{:.1},{gps_speed:.1},{raw}", throttle_position.raw(&buf), motor_current.value(&buf)).map_err(|e| format!("{e}"))?;
+            file.flush().map_err(|e| format!("{e}"))?;
+
+            let captured = frame_stats.ok + frame_stats.unverified;
+            if captured % 100 == 0 {
+                println!("{captured} frames captured, {} with a mismatched address or checksum, {} rejected", frame_stats.unverified, frame_stats.rejected);
+            }
+        }
+
+        thread::sleep(CAPTURE_RATE);
+    }
+}
+
+fn parse_capture(path: &str) -> Result<Vec<Sample>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    let mut samples = Vec::new();
+
+    for (line_number, line) in contents.lines().enumerate().skip(1) {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 5 || fields[4].len() != MSG_LEN * 2 {
+            println!("Skipping malformed line {}", line_number + 1);
+            continue;
+        }
+
+        let mut raw = [0u8; MSG_LEN];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&fields[4][i * 2..i * 2 + 2], 16).map_err(|_| format!("Invalid hex on line {}", line_number + 1))?;
+        }
+
+        let mut known = [f64::NAN; 3];
+        for (i, value) in known.iter_mut().enumerate() {
+            *value = fields[i + 1].parse().unwrap_or(f64::NAN);
+        }
+
+        samples.push(Sample { raw, known });
+    }
+
+    Ok(samples)
+}
+
+// raw responses from a capture, for replaying through the service
+pub fn load_frames(path: &str) -> Result<Vec<[u8; MSG_LEN]>, String> {
+    Ok(parse_capture(path)?.into_iter().map(|s| s.raw).collect())
+}
+
+// report variance, correlation with known signals and candidate scalings for each unknown word
+pub fn analyze(path: &str) -> Result<(), String> {
+    let samples = parse_capture(path)?;
+    if samples.len() < 2 {
+        return Err(format!("Not enough samples in {path}"));
+    }
+
+    // the unconfirmed address echo and checksum, a capture with no mismatches confirms both
+    let request = LIVE.request();
+    let mut layout_stats = FrameStats::default();
+    for sample in &samples {
+        match frame::check_layout(&request, &sample.raw) {
+            Ok(_) => layout_stats.record(&Ok(())),
+            Err(e) => layout_stats.record_unverified(&e),
+        }
+    }
+
+    println!("{} samples, {} address mismatches, {} checksum mismatches\n", samples.len(), layout_stats.address_mismatch, layout_stats.checksum);
+    println!("{:<9} {:>8} {:>10} {:>10} {:>12} {:>8}  candidates", "field", "type", "min", "max", "std_dev", "distinct");
+
+    // live data words that are not decoded yet
+    for field in LIVE.fields.iter().filter(|f| f.name.starts_with("uk_")) {
+        let (name, offset) = (field.name, field.offset);
+
+        for signed in [true, false] {
+            let values: Vec<f64> = samples
+                .iter()
+                .map(|s| match signed {
+                    true => bytes_to_word_signed(s.raw[offset], s.raw[offset + 1]) as f64,
+                    false => bytes_to_word_unsigned(s.raw[offset], s.raw[offset + 1]) as f64,
+                })
+                .collect();
+
+            // unsigned is only interesting when the signed view goes negative
+            if !signed && values.iter().all(|v| *v < 32768.0) {
+                continue;
+            }
+
+            let (mean, std_dev) = mean_std_dev(&values);
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let mut distinct = values.clone();
+            distinct.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            distinct.dedup();
+
+            let mut candidates = Vec::new();
+            if std_dev == 0.0 {
+                candidates.push(format!("constant {mean}"));
+            } else if values.windows(2).all(|w| w[1] >= w[0]) {
+                candidates.push("monotonic counter".to_string());
+            } else if distinct.len() <= 16 && distinct.iter().all(|v| *v >= 0.0) {
+                candidates.push("flags or state".to_string());
+            }
+
+            for (i, signal) in KNOWN_SIGNALS.iter().enumerate() {
+                let known: Vec<f64> = samples.iter().map(|s| s.known[i]).collect();
+                if let Some((r, scale, offset)) = fit(&values, &known) {
+                    if r.abs() >= MIN_CORRELATION {
+                        candidates.push(format!("r={r:.2} {signal} = {scale:.4} * raw + {offset:.2}"));
+                    }
+                }
+            }
+
+            let kind = if signed { "i16" } else { "u16" };
+            println!("{name:<9} {kind:>8} {min:>10} {max:>10} {std_dev:>12.2} {:>8}  {}", distinct.len(), candidates.join("; "));
+        }
+    }
+
+    // bits of the flag words that changed, to match against faults raised while capturing
+    println!("\n{:<9} {:>6} {:>8} {:>8} {:>8}", "field", "mask", "set", "first", "last");
+    for name in FLAG_WORDS {
+        let Some(field) = LIVE.field(name) else { continue };
+
+        for bit in 0..16 {
+            let mask = 1u16 << bit;
+            let set: Vec<usize> = samples.iter().enumerate().filter(|(_, s)| field.raw(&s.raw) as u16 & mask != 0).map(|(i, _)| i).collect();
+            if set.is_empty() || set.len() == samples.len() {
+                continue;
+            }
+
+            println!("{name:<9} {:>6} {:>8} {:>8} {:>8}", format!("{mask:#06x}"), set.len(), set[0], set[set.len() - 1]);
+        }
+    }
+
+    Ok(())
+}
+
+fn mean_std_dev(values: &[f64]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+
+    (mean, variance.sqrt())
+}
+
+// pearson correlation and least squares fit known = scale * raw + offset, skipping samples without a known value
+fn fit(raw: &[f64], known: &[f64]) -> Option<(f64, f64, f64)> {
+    let pairs: Vec<(f64, f64)> = raw.iter().zip(known).filter(|(_, k)| k.is_finite()).map(|(r, k)| (*r, *k)).collect();
+    if pairs.len() < 2 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let mut cov = 0.0;
+    let mut var_x = 0.0;
+    let mut var_y = 0.0;
+    for (x, y) in pairs.iter() {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+
+    let r = cov / (var_x.sqrt() * var_y.sqrt());
+    let scale = cov / var_x;
+    let offset = mean_y - scale * mean_x;
+
+    Some((r, scale, offset))
+}
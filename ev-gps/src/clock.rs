@@ -0,0 +1,180 @@
+// system clock discipline through an ntpd/chrony shared memory refclock
+use std::ptr::{self, addr_of, addr_of_mut};
+use std::sync::atomic::{fence, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+use nmea_parser::chrono::{DateTime, Utc};
+
+const SHM_KEY_BASE: i32 = 0x4e54_5030; // "NTP0", unit n is at key + n
+const SHM_MODE: i32 = 1; // count is incremented before and after writing so the reader can detect a torn sample
+const NMEA_PRECISION: i32 = -1; // log2 seconds, about 0.5 s for sentence timing
+const READ_RATE: Duration = Duration::from_millis(250); // rate to poll the segment when reading it back
+
+// shared memory layout used by ntpd, chrony and gpsd
+#[repr(C)]
+struct ShmTime {
+    mode: libc::c_int,
+    count: libc::c_int,
+    clock_sec: libc::time_t, // gps time
+    clock_usec: libc::c_int,
+    receive_sec: libc::time_t, // system time the sentence was received
+    receive_usec: libc::c_int,
+    leap: libc::c_int,
+    precision: libc::c_int,
+    nsamples: libc::c_int,
+    valid: libc::c_int,
+    clock_nsec: libc::c_uint,
+    receive_nsec: libc::c_uint,
+    dummy: [libc::c_int; 8],
+}
+
+pub struct ShmRefclock {
+    unit: u8,
+    shm: *mut ShmTime,
+}
+
+// attach to the segment for a unit, created if chrony has not started yet
+fn attach(unit: u8) -> Result<*mut ShmTime, String> {
+    // ntpd's convention, units 0 and 1 are root only, higher units can be written by a normal user
+    // chrony creates the segment itself with its refclock perm option, 0600 by default
+    let permissions = if unit < 2 { 0o600 } else { 0o666 };
+
+    unsafe {
+        let id = libc::shmget(SHM_KEY_BASE + unit as i32, std::mem::size_of::<ShmTime>(), libc::IPC_CREAT | permissions);
+        if id == -1 {
+            return Err(format!("Failed to get SHM unit {unit}: {}", std::io::Error::last_os_error()));
+        }
+
+        let shm = libc::shmat(id, ptr::null(), 0);
+        if shm as isize == -1 {
+            return Err(format!("Failed to attach SHM unit {unit}: {}", std::io::Error::last_os_error()));
+        }
+
+        Ok(shm as *mut ShmTime)
+    }
+}
+
+fn split(time: DateTime<Utc>) -> (libc::time_t, libc::c_uint) {
+    (time.timestamp() as libc::time_t, time.timestamp_subsec_nanos())
+}
+
+impl ShmRefclock {
+    pub fn open(unit: u8) -> Result<ShmRefclock, String> {
+        let shm = attach(unit)?;
+        println!("Feeding GPS time to SHM unit {unit}");
+        Ok(ShmRefclock { unit, shm })
+    }
+
+    // hand a sample to the time daemon, which reads it and clears valid
+    pub fn write(&mut self, gps_time: DateTime<Utc>, received: DateTime<Utc>) {
+        let (clock_sec, clock_nsec) = split(gps_time);
+        let (receive_sec, receive_nsec) = split(received);
+
+        unsafe {
+            let shm = self.shm;
+            ptr::write_volatile(addr_of_mut!((*shm).valid), 0);
+            ptr::write_volatile(addr_of_mut!((*shm).mode), SHM_MODE);
+
+            let count = ptr::read_volatile(addr_of!((*shm).count));
+            ptr::write_volatile(addr_of_mut!((*shm).count), count.wrapping_add(1));
+            fence(Ordering::SeqCst);
+
+            ptr::write_volatile(addr_of_mut!((*shm).clock_sec), clock_sec);
+            ptr::write_volatile(addr_of_mut!((*shm).clock_usec), (clock_nsec / 1000) as libc::c_int);
+            ptr::write_volatile(addr_of_mut!((*shm).clock_nsec), clock_nsec);
+            ptr::write_volatile(addr_of_mut!((*shm).receive_sec), receive_sec);
+            ptr::write_volatile(addr_of_mut!((*shm).receive_usec), (receive_nsec / 1000) as libc::c_int);
+            ptr::write_volatile(addr_of_mut!((*shm).receive_nsec), receive_nsec);
+            ptr::write_volatile(addr_of_mut!((*shm).leap), 0); // no leap second warning
+            ptr::write_volatile(addr_of_mut!((*shm).precision), NMEA_PRECISION);
+            ptr::write_volatile(addr_of_mut!((*shm).nsamples), 0);
+
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(addr_of_mut!((*shm).count), count.wrapping_add(2));
+            ptr::write_volatile(addr_of_mut!((*shm).valid), 1);
+        }
+    }
+}
+
+impl Drop for ShmRefclock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::shmdt(self.shm as *const libc::c_void);
+        }
+        println!("Detached from SHM unit {}", self.unit);
+    }
+}
+
+// gps time to system clock and the shm feed, only while the receiver has a valid fix
+pub struct Clock {
+    pub refclock: Option<ShmRefclock>,
+    time_valid: bool, // from the rmc status, zda has no validity of its own
+    last_time: Option<DateTime<Utc>>,
+}
+
+impl Clock {
+    pub fn new(refclock: Option<ShmRefclock>) -> Clock {
+        Clock { refclock, time_valid: false, last_time: None }
+    }
+
+    pub fn set_valid(&mut self, valid: bool) {
+        self.time_valid = valid;
+    }
+
+    // offset of the system clock from gps time in ms, positive when the system clock is ahead,
+    // none while the time is not valid or for a repeat of the last time e.g. zda after rmc
+    pub fn sample(&mut self, gps_time: DateTime<Utc>, received: DateTime<Utc>) -> Option<i64> {
+        if !self.time_valid || self.last_time.is_some_and(|last| gps_time <= last) {
+            return None;
+        }
+        self.last_time = Some(gps_time);
+
+        if let Some(refclock) = self.refclock.as_mut() {
+            refclock.write(gps_time, received);
+        }
+
+        Some((received - gps_time).num_milliseconds())
+    }
+}
+
+// print samples from a unit the way chrony reads them, for testing without chronyd
+pub fn read(unit: u8) -> Result<(), String> {
+    let shm = attach(unit)?;
+    println!("Reading SHM unit {unit}, stop chronyd first as reading clears each sample");
+
+    loop {
+        unsafe {
+            if ptr::read_volatile(addr_of!((*shm).valid)) == 1 {
+                let count = ptr::read_volatile(addr_of!((*shm).count));
+                fence(Ordering::SeqCst);
+                let sample = ptr::read_volatile(shm);
+                fence(Ordering::SeqCst);
+                let torn = sample.mode == 1 && ptr::read_volatile(addr_of!((*shm).count)) != count;
+                ptr::write_volatile(addr_of_mut!((*shm).valid), 0);
+
+                let seconds = u64::try_from(sample.clock_sec).ok().zip(u64::try_from(sample.receive_sec).ok());
+
+                if torn {
+                    println!("Sample changed while reading, skipped");
+                } else if let Some((clock_sec, receive_sec)) = seconds {
+                    let clock = UNIX_EPOCH + Duration::new(clock_sec, sample.clock_nsec);
+                    let receive = UNIX_EPOCH + Duration::new(receive_sec, sample.receive_nsec);
+                    let clock: DateTime<Utc> = clock.into();
+                    let receive: DateTime<Utc> = receive.into();
+
+                    println!(
+                        "clock {} receive {} offset {} ms leap {} precision {}",
+                        clock.format("%H:%M:%S%.3f"),
+                        receive.format("%H:%M:%S%.3f"),
+                        (receive - clock).num_milliseconds(),
+                        sample.leap,
+                        sample.precision
+                    );
+                } else {
+                    println!("Sample with negative seconds, clock {} receive {}, skipped", sample.clock_sec, sample.receive_sec);
+                }
+            }
+        }
+        std::thread::sleep(READ_RATE);
+    }
+}
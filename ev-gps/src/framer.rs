@@ -0,0 +1,212 @@
+// splits the serial byte stream into nmea sentences and ubx frames, dropping anything malformed
+use crate::ubx::{self, Frame};
+
+const MAX_SENTENCE: usize = 120; // nmea limits sentences to 82 bytes, some receivers send longer proprietary ones
+const UBX_HEADER_LEN: usize = 6; // sync, class, id and length
+const MAX_UBX_PAYLOAD: usize = 2048; // larger than any message ev-gps asks for, longer lengths are corrupt
+
+pub enum Packet {
+    Nmea(String),
+    Ubx(Frame),
+}
+
+// counts of accepted and dropped sentences
+#[derive(Default)]
+pub struct FramerStats {
+    pub ok: u32,
+    pub overlong: u32,      // no line ending within MAX_SENTENCE bytes
+    pub invalid: u32,       // non ascii or control bytes, line noise or a baud rate mismatch
+    pub checksum: u32,      // checksum present and wrong
+    pub resync: u32,        // sentence cut short by the start of another
+    pub garbage_bytes: u32, // bytes outside any sentence
+    pub ubx_ok: u32,
+    pub ubx_invalid: u32,   // ubx length too long or checksum wrong
+}
+
+#[derive(Default)]
+pub struct Framer {
+    buf: Vec<u8>, // current sentence or frame, empty when waiting for a start byte
+    ubx: bool,    // buf holds a ubx frame
+    pub stats: FramerStats,
+}
+
+impl FramerStats {
+    pub fn rejected(&self) -> u32 {
+        self.overlong + self.invalid + self.checksum + self.resync + self.ubx_invalid
+    }
+
+    // influxdb line protocol fields
+    pub fn fields(&self) -> String {
+        format!(
+            "sentences_ok={},sentences_rejected={},sentences_overlong={},sentences_invalid={},sentences_checksum={},sentences_resync={},garbage_bytes={},ubx_ok={},ubx_invalid={}",
+            self.ok,
+            self.rejected(),
+            self.overlong,
+            self.invalid,
+            self.checksum,
+            self.resync,
+            self.garbage_bytes,
+            self.ubx_ok,
+            self.ubx_invalid
+        )
+    }
+}
+
+impl Framer {
+    // drop a partial sentence or frame from the last port, keeping the counts
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.ubx = false;
+    }
+
+    // add bytes read from the port, returning any complete sentences without the line ending and ubx frames
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Packet> {
+        let mut packets = Vec::new();
+
+        for &byte in bytes {
+            if self.ubx {
+                // a lone sync byte is line noise, the byte after it is handled as if the sync byte wasn't there
+                if self.buf.len() == 1 && byte != ubx::SYNC[1] {
+                    self.stats.garbage_bytes += 1;
+                    self.end_ubx();
+                } else {
+                    if let Some(frame) = self.push_ubx(byte) {
+                        packets.push(Packet::Ubx(frame));
+                    }
+                    continue;
+                }
+            }
+
+            match byte {
+                // ubx frames start between sentences
+                _ if byte == ubx::SYNC[0] && self.buf.is_empty() => {
+                    self.ubx = true;
+                    self.buf.push(byte);
+                }
+                // $ starts nmea sentences, ! starts ais sentences
+                b'$' | b'!' => {
+                    if !self.buf.is_empty() {
+                        self.stats.resync += 1;
+                        self.buf.clear();
+                    }
+                    self.buf.push(byte);
+                }
+                b'\r' | b'\n' => {
+                    if !self.buf.is_empty() {
+                        if let Some(sentence) = self.finish() {
+                            packets.push(Packet::Nmea(sentence));
+                        }
+                        self.buf.clear();
+                    }
+                }
+                _ if self.buf.is_empty() => self.stats.garbage_bytes += 1,
+                _ if self.buf.len() >= MAX_SENTENCE => {
+                    // drop the rest of the line, wait for the next start byte
+                    self.stats.overlong += 1;
+                    self.buf.clear();
+                }
+                _ => self.buf.push(byte),
+            }
+        }
+
+        packets
+    }
+
+    fn push_ubx(&mut self, byte: u8) -> Option<Frame> {
+        self.buf.push(byte);
+        if self.buf.len() < UBX_HEADER_LEN {
+            return None;
+        }
+
+        let payload_len = u16::from_le_bytes([self.buf[4], self.buf[5]]) as usize;
+        if payload_len > MAX_UBX_PAYLOAD {
+            self.stats.ubx_invalid += 1;
+            self.end_ubx();
+            return None;
+        }
+        if self.buf.len() < UBX_HEADER_LEN + payload_len + 2 {
+            return None;
+        }
+
+        let body = &self.buf[2..UBX_HEADER_LEN + payload_len];
+        let frame = if ubx::checksum(body) == self.buf[UBX_HEADER_LEN + payload_len..] {
+            self.stats.ubx_ok += 1;
+            Some(Frame { class: self.buf[2], id: self.buf[3], payload: self.buf[UBX_HEADER_LEN..UBX_HEADER_LEN + payload_len].to_vec() })
+        } else {
+            self.stats.ubx_invalid += 1;
+            None
+        };
+
+        self.end_ubx();
+        frame
+    }
+
+    fn end_ubx(&mut self) {
+        self.ubx = false;
+        self.buf.clear();
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        if !self.buf.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            self.stats.invalid += 1;
+            return None;
+        }
+        let sentence = String::from_utf8(self.buf.clone()).ok()?; // ascii is always utf8
+
+        // checksum is the xor of everything between the start byte and *
+        if let Some((body, checksum)) = sentence[1..].split_once('*') {
+            let calculated = body.bytes().fold(0u8, |sum, b| sum ^ b);
+            if u8::from_str_radix(checksum, 16).ok() != Some(calculated) {
+                self.stats.checksum += 1;
+                return None;
+            }
+        }
+
+        self.stats.ok += 1;
+        Some(sentence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GGA: &[u8] = b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+
+    fn sentences(packets: Vec<Packet>) -> Vec<String> {
+        packets
+            .into_iter()
+            .filter_map(|p| match p {
+                Packet::Nmea(sentence) => Some(sentence),
+                Packet::Ubx(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lone_sync_byte_keeps_following_sentence() {
+        let mut framer = Framer::default();
+        let mut bytes = vec![ubx::SYNC[0]];
+        bytes.extend_from_slice(GGA);
+
+        let sentences = sentences(framer.push(&bytes));
+        assert_eq!(sentences, vec![String::from_utf8(GGA[..GGA.len() - 2].to_vec()).unwrap()]);
+        assert_eq!((framer.stats.ok, framer.stats.garbage_bytes, framer.stats.resync), (1, 1, 0));
+    }
+
+    #[test]
+    fn ubx_frame_between_sentences() {
+        let mut framer = Framer::default();
+        let body = [0x05, 0x01, 0x02, 0x00, 0x06, 0x01]; // ACK-ACK of CFG-MSG
+        let mut bytes = GGA.to_vec();
+        bytes.extend_from_slice(&ubx::SYNC);
+        bytes.extend_from_slice(&body);
+        bytes.extend_from_slice(&ubx::checksum(&body));
+        bytes.extend_from_slice(GGA);
+
+        let packets = framer.push(&bytes);
+        assert!(matches!(&packets[1], Packet::Ubx(frame) if frame.class == 0x05 && frame.id == 0x01 && frame.payload == [0x06, 0x01]));
+        assert_eq!(sentences(packets).len(), 2);
+        assert_eq!((framer.stats.ok, framer.stats.ubx_ok, framer.stats.rejected()), (2, 1, 0));
+    }
+}
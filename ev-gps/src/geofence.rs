@@ -0,0 +1,176 @@
+use std::fs;
+
+use crate::trip::haversine;
+
+const GEOFENCES_PATH: &str = "geofences.conf"; // name = circle lat,lon radius or name = polygon lat,lon lat,lon lat,lon ...
+pub const NO_LOCATION: &str = "none"; // outside every fence, an empty retained message would clear the topic
+const EXIT_MARGIN_M: f64 = 20.0; // distance beyond the boundary to count as leaving, so jitter at the edge doesn't flap
+const METERS_PER_DEGREE: f64 = 111_195.0; // along a meridian, on the same sphere as haversine
+
+enum Shape {
+    Circle { latitude: f64, longitude: f64, radius_m: f64 },
+    Polygon(Vec<(f64, f64)>), // latitude, longitude vertices, closed back to the first
+}
+
+struct Geofence {
+    name: String,
+    shape: Shape,
+    inside: bool,
+}
+
+pub struct GeofenceEvent {
+    pub name: String,
+    pub entered: bool, // false for an exit
+}
+
+#[derive(Default)]
+pub struct Geofences {
+    fences: Vec<Geofence>,
+}
+
+fn parse_point(text: &str) -> Option<(f64, f64)> {
+    let (latitude, longitude) = text.split_once(',')?;
+    let (latitude, longitude): (f64, f64) = (latitude.trim().parse().ok()?, longitude.trim().parse().ok()?);
+
+    if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+        return None;
+    }
+    Some((latitude, longitude))
+}
+
+// distance in meters from the origin to the segment a-b, in a local flat projection
+fn segment_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    let t = if length > 0.0 { (-(a.0 * dx + a.1 * dy) / length).clamp(0.0, 1.0) } else { 0.0 };
+
+    (a.0 + t * dx).hypot(a.1 + t * dy)
+}
+
+impl Shape {
+    // meters outside the boundary, 0 when inside
+    fn distance_outside(&self, latitude: f64, longitude: f64) -> f64 {
+        match self {
+            Shape::Circle { latitude: center_lat, longitude: center_lon, radius_m } => {
+                (haversine(*center_lat, *center_lon, latitude, longitude) - radius_m).max(0.0)
+            }
+            Shape::Polygon(vertices) => {
+                // project the vertices to meters around the position, fine for fences a few km across
+                let scale = latitude.to_radians().cos();
+                let points: Vec<(f64, f64)> = vertices
+                    .iter()
+                    .map(|(lat, lon)| ((lon - longitude) * scale * METERS_PER_DEGREE, (lat - latitude) * METERS_PER_DEGREE))
+                    .collect();
+
+                let mut inside = false;
+                let mut distance = f64::MAX;
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+
+                    // ray cast along +x from the position at the origin
+                    if (a.1 > 0.0) != (b.1 > 0.0) && a.0 + (b.0 - a.0) * -a.1 / (b.1 - a.1) > 0.0 {
+                        inside = !inside;
+                    }
+                    distance = distance.min(segment_distance(*a, b));
+                }
+
+                if inside { 0.0 } else { distance }
+            }
+        }
+    }
+}
+
+// parse a geofence file, # starts a comment
+fn parse_geofences(contents: &str) -> Result<Vec<Geofence>, String> {
+    let mut fences: Vec<Geofence> = Vec::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("Line {}: {message}", line_number + 1);
+
+        let (name, definition) = line.split_once('=').ok_or_else(|| error("expected name = circle or name = polygon"))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(error(&format!("invalid name '{name}', use letters, numbers, _ or -")));
+        }
+        if name == NO_LOCATION {
+            return Err(error(&format!("{NO_LOCATION} is reserved for being outside every fence")));
+        }
+        if fences.iter().any(|f| f.name == name) {
+            return Err(error(&format!("duplicate name {name}")));
+        }
+
+        let mut parts = definition.split_whitespace();
+        let shape = match parts.next() {
+            Some("circle") => {
+                let (latitude, longitude) = parts.next().and_then(parse_point).ok_or_else(|| error("expected circle lat,lon radius"))?;
+                let radius_m: f64 = parts.next().and_then(|r| r.parse().ok()).filter(|r| *r > 0.0).ok_or_else(|| error("expected a radius in meters"))?;
+                Shape::Circle { latitude, longitude, radius_m }
+            }
+            Some("polygon") => {
+                let vertices = parts.by_ref().map(parse_point).collect::<Option<Vec<_>>>().ok_or_else(|| error("invalid polygon point, expected lat,lon"))?;
+                if vertices.len() < 3 {
+                    return Err(error("a polygon needs at least 3 points"));
+                }
+                Shape::Polygon(vertices)
+            }
+            _ => return Err(error("expected circle or polygon")),
+        };
+        if parts.next().is_some() {
+            return Err(error("unexpected values after the shape"));
+        }
+
+        fences.push(Geofence { name: name.to_string(), shape, inside: false });
+    }
+
+    Ok(fences)
+}
+
+impl Geofences {
+    // load geofences from disk, none if there is no file
+    pub fn load() -> Geofences {
+        let contents = match fs::read_to_string(GEOFENCES_PATH) {
+            Ok(c) => c,
+            Err(_) => {
+                println!("No geofences in {GEOFENCES_PATH}");
+                return Geofences::default();
+            }
+        };
+
+        match parse_geofences(&contents) {
+            Ok(fences) => {
+                println!("Loaded {} geofences from {GEOFENCES_PATH}", fences.len());
+                Geofences { fences }
+            }
+            Err(e) => {
+                println!("Error in {GEOFENCES_PATH}: {e}, geofencing disabled");
+                Geofences::default()
+            }
+        }
+    }
+
+    // enter and exit events for a valid fix
+    pub fn update(&mut self, latitude: f64, longitude: f64) -> Vec<GeofenceEvent> {
+        let mut events = Vec::new();
+
+        for fence in self.fences.iter_mut() {
+            let distance = fence.shape.distance_outside(latitude, longitude);
+
+            let inside = if fence.inside { distance <= EXIT_MARGIN_M } else { distance == 0.0 };
+            if inside != fence.inside {
+                fence.inside = inside;
+                events.push(GeofenceEvent { name: fence.name.clone(), entered: inside });
+            }
+        }
+
+        events
+    }
+
+    // first fence in file order the position is inside, so list small fences before ones that contain them
+    pub fn location_name(&self) -> &str {
+        self.fences.iter().find(|f| f.inside).map_or(NO_LOCATION, |f| f.name.as_str())
+    }
+}
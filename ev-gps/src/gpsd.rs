@@ -0,0 +1,408 @@
+// gpsd json protocol server so other tools can share the gps, https://gpsd.gitlab.io/gpsd/gpsd_json.html
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use nmea_parser::chrono::{DateTime, SecondsFormat, Utc};
+use nmea_parser::gnss::{GsaFixMode, GsvData, NavigationSystem};
+use nmea_parser::ParsedMessage;
+
+use crate::sentences::Gst;
+
+const PROTO_MAJOR: u32 = 3;
+const PROTO_MINOR: u32 = 14;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1); // a client slower than this is dropped
+const QUEUE_LEN: usize = 64; // lines waiting for a client's writer, a client this far behind is dropped so it can't hold up the gps
+const KNOTS_TO_MPS: f64 = 1852.0 / 3600.0;
+const NO_DEVICES: &str = "{\"class\":\"DEVICES\",\"devices\":[]}";
+
+#[derive(Clone, Copy, Default)]
+struct Watch {
+    enable: bool,
+    json: bool,
+    nmea: bool,
+}
+
+struct Watcher {
+    id: u64,
+    stream: TcpStream, // only used to disconnect, lines go through the queue
+    queue: SyncSender<String>, // to the client's writer thread
+    watch: Watch,
+}
+
+#[derive(Default)]
+struct Shared {
+    watchers: Vec<Watcher>,
+    next_id: u64,
+    tpv: Option<String>, // last reports for ?POLL
+    sky: Option<String>,
+    devices: String, // DEVICES report, the gps once it is found
+}
+
+// latest values from each sentence type, combined into tpv and sky reports
+#[derive(Default)]
+struct Fix {
+    time: Option<DateTime<Utc>>,
+    active: bool,
+    gsa_mode: Option<u8>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>, // above mean sea level
+    geoid_separation: Option<f64>,
+    speed: Option<f64>, // m/s
+    track: Option<f64>,
+    epx: Option<f64>,
+    epy: Option<f64>,
+    epv: Option<f64>,
+    hdop: Option<f64>,
+    vdop: Option<f64>,
+    pdop: Option<f64>,
+    used: BTreeMap<String, Vec<u8>>, // prns used in the fix from each system's gsa
+    satellites: BTreeMap<String, Vec<GsvData>>, // satellites in view from each system's gsv
+}
+
+pub struct GpsdServer {
+    device: String,
+    shared: Arc<Mutex<Shared>>,
+    fix: Fix,
+}
+
+// "name":value for the values that are known
+fn json_fields(fields: &mut Vec<String>, values: &[(&str, Option<f64>, usize)]) {
+    for (name, value, decimals) in values {
+        if let Some(value) = value {
+            fields.push(format!("\"{name}\":{value:.decimals$}"));
+        }
+    }
+}
+
+// a boolean member of a watch object, e.g. "enable":true
+fn json_bool(json: &str, name: &str) -> Option<bool> {
+    let rest = &json[json.find(&format!("\"{name}\""))? + name.len() + 2..];
+    let value = rest.trim_start().strip_prefix(':')?.trim_start();
+
+    if value.starts_with("true") {
+        Some(true)
+    } else if value.starts_with("false") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// a json string value, for text that came from a client
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn system_name(system: &NavigationSystem) -> String {
+    format!("{:?}", system).to_lowercase()
+}
+
+// u-blox gnss ids as used by gpsd
+fn gnss_id(system: &str) -> Option<u8> {
+    match system {
+        "gps" => Some(0),
+        "galileo" => Some(2),
+        "beidou" => Some(3),
+        "qzss" => Some(5),
+        "glonass" => Some(6),
+        "navic" => Some(7),
+        _ => None,
+    }
+}
+
+fn version() -> String {
+    let release = env!("CARGO_PKG_VERSION");
+    format!("{{\"class\":\"VERSION\",\"release\":\"ev-gps {release}\",\"rev\":\"ev-gps {release}\",\"proto_major\":{PROTO_MAJOR},\"proto_minor\":{PROTO_MINOR}}}")
+}
+
+fn watch_json(watch: &Watch) -> String {
+    format!("{{\"class\":\"WATCH\",\"enable\":{},\"json\":{},\"nmea\":{},\"raw\":0,\"scaled\":false,\"timing\":false,\"split24\":false,\"pps\":false}}", watch.enable, watch.json, watch.nmea)
+}
+
+impl GpsdServer {
+    // listen on localhost, clients are served from their own threads
+    pub fn start(port: u16) -> Result<GpsdServer, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Failed to listen on port {port}: {e}"))?;
+        println!("Serving gpsd json on port {port}");
+
+        let shared = Arc::new(Mutex::new(Shared { devices: NO_DEVICES.to_string(), ..Default::default() }));
+
+        let accept_shared = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => accept(stream, &accept_shared),
+                    Err(e) => println!("gpsd client failed to connect: {e}"),
+                }
+            }
+        });
+
+        Ok(GpsdServer { device: String::new(), shared, fix: Fix::default() })
+    }
+
+    // the port the gps was found on, reported to watching clients
+    pub fn set_device(&mut self, path: &str, baud_rate: u32) {
+        let devices = format!(
+            "{{\"class\":\"DEVICES\",\"devices\":[{{\"class\":\"DEVICE\",\"path\":\"{path}\",\"driver\":\"NMEA0183\",\"activated\":\"{}\",\"flags\":1,\"native\":0,\"bps\":{baud_rate},\"parity\":\"N\",\"stopbits\":1}}]}}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+        );
+
+        self.device = path.to_string();
+        self.send(|watch| watch.json, &devices);
+        self.shared.lock().unwrap().devices = devices;
+    }
+
+    // the gps is gone, no devices until it is found again
+    pub fn clear_device(&mut self) {
+        self.device.clear();
+        self.fix = Fix::default();
+        self.send(|watch| watch.json, NO_DEVICES);
+
+        let mut shared = self.shared.lock().unwrap();
+        shared.devices = NO_DEVICES.to_string();
+        shared.tpv = None;
+        shared.sky = None;
+    }
+
+    // raw sentence for clients watching with "nmea":true
+    pub fn nmea(&mut self, sentence: &str) {
+        self.send(|watch| watch.nmea, sentence);
+    }
+
+    pub fn update(&mut self, sentence: &ParsedMessage) {
+        match sentence {
+            // one tpv per fix, rmc comes once per epoch
+            ParsedMessage::Rmc(rmc) => {
+                self.fix.time = rmc.timestamp.or(self.fix.time);
+                self.fix.active = rmc.status_active == Some(true);
+                self.fix.latitude = rmc.latitude;
+                self.fix.longitude = rmc.longitude;
+                self.fix.speed = rmc.sog_knots.map(|knots| knots * KNOTS_TO_MPS);
+                self.fix.track = rmc.bearing;
+
+                let tpv = self.tpv();
+                self.send(|watch| watch.json, &tpv);
+                self.shared.lock().unwrap().tpv = Some(tpv);
+            }
+            ParsedMessage::Gga(gga) => {
+                self.fix.altitude = gga.altitude;
+                self.fix.geoid_separation = gga.geoid_separation;
+            }
+            ParsedMessage::Gsa(gsa) => {
+                self.fix.gsa_mode = gsa.mode2_3d.as_ref().map(|mode| match mode {
+                    GsaFixMode::NotAvailable => 1,
+                    GsaFixMode::Fix2D => 2,
+                    GsaFixMode::Fix3D => 3,
+                });
+                self.fix.hdop = gsa.hdop;
+                self.fix.vdop = gsa.vdop;
+                self.fix.pdop = gsa.pdop;
+                self.fix.used.insert(system_name(&gsa.source), gsa.prn_numbers.clone());
+            }
+            // all satellites of one system, sky lists every system
+            ParsedMessage::Gsv(satellites) => {
+                let Some(first) = satellites.first() else { return };
+                self.fix.satellites.insert(system_name(&first.source), satellites.clone());
+
+                let sky = self.sky();
+                self.send(|watch| watch.json, &sky);
+                self.shared.lock().unwrap().sky = Some(sky);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn update_gst(&mut self, gst: &Gst) {
+        self.fix.epx = gst.lon_error;
+        self.fix.epy = gst.lat_error;
+        self.fix.epv = gst.alt_error;
+    }
+
+    // 0 unknown, 1 no fix, 2 2d, 3 3d
+    fn mode(&self) -> u8 {
+        match (self.fix.active, self.fix.gsa_mode) {
+            (false, _) => 1,
+            (true, Some(mode)) => mode.max(2),
+            (true, None) if self.fix.altitude.is_some() => 3,
+            (true, None) => 2,
+        }
+    }
+
+    fn tpv(&self) -> String {
+        let mode = self.mode();
+        let mut fields = vec!["\"class\":\"TPV\"".to_string(), format!("\"device\":\"{}\"", self.device), format!("\"mode\":{mode}")];
+
+        if let Some(time) = self.fix.time {
+            fields.push(format!("\"time\":\"{}\"", time.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        }
+        if mode >= 2 {
+            let altitude = if mode == 3 { self.fix.altitude } else { None };
+            let altitude_hae = altitude.and_then(|altitude| Some(altitude + self.fix.geoid_separation?));
+
+            json_fields(&mut fields, &[
+                ("lat", self.fix.latitude, 9),
+                ("lon", self.fix.longitude, 9),
+                ("alt", altitude, 3),
+                ("altMSL", altitude, 3),
+                ("altHAE", altitude_hae, 3),
+                ("geoidSep", self.fix.geoid_separation, 3),
+                ("track", self.fix.track, 4),
+                ("speed", self.fix.speed, 3),
+                ("epx", self.fix.epx, 3),
+                ("epy", self.fix.epy, 3),
+                ("epv", self.fix.epv, 3),
+            ]);
+        }
+
+        format!("{{{}}}", fields.join(","))
+    }
+
+    fn sky(&self) -> String {
+        let mut satellites = Vec::new();
+        let mut used_count = 0;
+        for (system, in_view) in self.fix.satellites.iter() {
+            // prns overlap between systems, so only the system's own gsa counts, or the combined one from receivers without per system gsa
+            let used = self.fix.used.get(system).or_else(|| self.fix.used.get("combination"));
+            for sat in in_view.iter() {
+                let mut fields = vec![format!("\"PRN\":{}", sat.prn_number)];
+                if let Some(gnss_id) = gnss_id(system) {
+                    fields.push(format!("\"gnssid\":{gnss_id}"));
+                }
+                json_fields(&mut fields, &[
+                    ("el", sat.elevation.map(f64::from), 0),
+                    ("az", sat.azimuth.map(f64::from), 0),
+                    ("ss", sat.snr.map(f64::from), 0),
+                ]);
+                let sat_used = used.is_some_and(|used| used.contains(&sat.prn_number));
+                used_count += sat_used as usize;
+                fields.push(format!("\"used\":{sat_used}"));
+                satellites.push(format!("{{{}}}", fields.join(",")));
+            }
+        }
+
+        let mut fields = vec!["\"class\":\"SKY\"".to_string(), format!("\"device\":\"{}\"", self.device)];
+        if let Some(time) = self.fix.time {
+            fields.push(format!("\"time\":\"{}\"", time.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        }
+        json_fields(&mut fields, &[("hdop", self.fix.hdop, 2), ("vdop", self.fix.vdop, 2), ("pdop", self.fix.pdop, 2)]);
+        fields.push(format!("\"nSat\":{},\"uSat\":{}", satellites.len(), used_count));
+        fields.push(format!("\"satellites\":[{}]", satellites.join(",")));
+
+        format!("{{{}}}", fields.join(","))
+    }
+
+    // queue a line for each client whose watch wants it, never blocking on a client
+    fn send(&self, wanted: impl Fn(&Watch) -> bool, line: &str) {
+        let mut shared = self.shared.lock().unwrap();
+
+        shared.watchers.retain(|watcher| !watcher.watch.enable || !wanted(&watcher.watch) || watcher.queue_line(line.to_string()));
+    }
+}
+
+impl Watcher {
+    // false when the client is gone or too far behind, it is disconnected
+    fn queue_line(&self, line: String) -> bool {
+        if self.queue.try_send(line).is_ok() {
+            return true;
+        }
+
+        let _ = self.stream.shutdown(Shutdown::Both); // ends the reader and writer threads
+        false
+    }
+}
+
+fn accept(stream: TcpStream, shared: &Arc<Mutex<Shared>>) {
+    let (Ok(reader), Ok(mut writer), Ok(_)) = (stream.try_clone(), stream.try_clone(), stream.set_write_timeout(Some(WRITE_TIMEOUT))) else { return };
+
+    // lines are written from the client's own thread, so a slow client only fills its queue
+    let (queue, lines) = mpsc::sync_channel::<String>(QUEUE_LEN);
+    thread::spawn(move || {
+        for line in lines.iter() {
+            if writeln!(writer, "{line}").is_err() {
+                let _ = writer.shutdown(Shutdown::Both);
+                break;
+            }
+        }
+    });
+
+    let id = {
+        let mut shared = shared.lock().unwrap();
+        let id = shared.next_id;
+        shared.next_id += 1;
+
+        let watcher = Watcher { id, stream, queue, watch: Watch::default() };
+        if !watcher.queue_line(version()) {
+            return;
+        }
+        shared.watchers.push(watcher);
+        id
+    };
+
+    let shared = shared.clone();
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else { break };
+
+            // several commands can share a line, each ends with ;
+            for command in line.split(';').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+                let mut shared = shared.lock().unwrap();
+                let replies = respond(&mut shared, id, command);
+
+                let Some(watcher) = shared.watchers.iter().find(|w| w.id == id) else { return };
+                if !replies.into_iter().all(|reply| watcher.queue_line(reply)) {
+                    shared.watchers.retain(|w| w.id != id);
+                    return;
+                }
+            }
+        }
+
+        shared.lock().unwrap().watchers.retain(|w| w.id != id);
+    });
+}
+
+fn respond(shared: &mut Shared, id: u64, command: &str) -> Vec<String> {
+    let (name, argument) = command.split_once('=').unwrap_or((command, ""));
+
+    match name {
+        "?VERSION" => vec![version()],
+        "?DEVICES" => vec![shared.devices.clone()],
+        "?WATCH" => {
+            let devices = shared.devices.clone();
+            let Some(watcher) = shared.watchers.iter_mut().find(|w| w.id == id) else { return Vec::new() };
+            let watch = &mut watcher.watch;
+
+            if !argument.is_empty() {
+                watch.enable = json_bool(argument, "enable").unwrap_or(true);
+                watch.json = json_bool(argument, "json").unwrap_or(watch.json);
+                watch.nmea = json_bool(argument, "nmea").unwrap_or(watch.nmea);
+                // enabling with neither json nor nmea means json
+                if watch.enable && !watch.json && !watch.nmea {
+                    watch.json = true;
+                }
+            }
+            vec![devices, watch_json(watch)]
+        }
+        "?POLL" => {
+            let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            let tpv = shared.tpv.clone().unwrap_or_default();
+            let sky = shared.sky.clone().unwrap_or_default();
+            vec![format!("{{\"class\":\"POLL\",\"time\":\"{time}\",\"active\":{},\"tpv\":[{tpv}],\"sky\":[{sky}]}}", shared.tpv.is_some() as u8)]
+        }
+        _ => vec![format!("{{\"class\":\"ERROR\",\"message\":\"Unrecognized request '{}'\"}}", json_escape(name.trim_start_matches('?')))],
+    }
+}
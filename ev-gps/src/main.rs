@@ -1,200 +1,593 @@
 use mqtt::Client;
 use serialport::SerialPort;
-use std::time::Duration;
-use std::process;
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
 use nmea_parser::*;
-use std::str;
+use nmea_parser::chrono::{DateTime, SecondsFormat, Utc};
 
 extern crate paho_mqtt as mqtt;
 
-const MSG_LEN: usize = 82; // message size
-const GPS_PATH: &str = "/dev/ttyGPS"; // path to usb gps
-const GPS_BAUD_RATE: u32 = 9600; // usb gps baud rate
+mod clock;
+mod framer;
+mod geofence;
+mod gpsd;
+mod port;
+mod quality;
+mod sentences;
+mod smoothing;
+mod trip;
+mod ubx;
+
+use clock::{Clock, ShmRefclock};
+use framer::{Framer, Packet};
+use geofence::Geofences;
+use gpsd::GpsdServer;
+use port::{GpsPort, Search};
+use quality::FixQuality;
+use smoothing::Smoother;
+use sentences::{Gst, Zda};
+use trip::{Odometer, TripEvent};
+use ubx::Pvt;
+
+const READ_LEN: usize = 256; // bytes read from the port at a time
+const MIN_RETRY: Duration = Duration::from_secs(1); // wait before searching again for the gps, doubling up to MAX_RETRY
+const MAX_RETRY: Duration = Duration::from_secs(30);
+const GPS_STATUS_TOPIC: &str = "live/gps/status"; // online or offline
 const MQTT_IP: &str = "tcp://127.0.0.1:1883";
 const MQTT_CLIENT_ID: &str = "gps";
+const PVT_TIMEOUT: Duration = Duration::from_secs(3); // without NAV-PVT for this long the clock is fed from rmc instead
+const FRAMER_STATS_RATE: Duration = Duration::from_secs(10); // rate to report accepted and rejected sentences
+const TRIP_RESET_TOPIC: &str = "trip/reset"; // payload a or b
+const CONTROLLER_STATUS_TOPIC: &str = "live/motor_controller/status"; // online or offline, the controller is only online with the key on
+const CONTROLLER_FLAG_TOPIC: &str = "live/motor_controller/fault_event"; // raised,key_on or cleared,key_on
 
 fn main() {
-    let mut reconnect = false;
-    let mut gps_port = connect_gps();
+    let mut args: Vec<String> = env::args().collect();
 
-    let mut serial_buffer = [0;1]; // buffer to store each byte as it's received
-    let mut message_buffer = [0;MSG_LEN]; // buffer to store a full message of 13 bytes
+    // optional shm refclock unit to feed gps time to chrony or ntpd, and port to serve gpsd json on
+    let shm_unit: Option<u8> = take_flag(&mut args, "--shm", "an SHM unit").map(|unit| parse_number(&unit, "SHM unit"));
+    let gpsd_port: Option<u16> = take_flag(&mut args, "--gpsd", "a port").map(|port| parse_number(&port, "gpsd port"));
+    let smooth = take_switch(&mut args, "--smooth"); // filter speed and heading before publishing
+
+    // configure a u-blox receiver for this navigation rate and decode NAV-PVT
+    let ubx_rate: Option<u16> = take_flag(&mut args, "--ubx", "a navigation rate in hz").map(|rate| parse_number(&rate, "navigation rate"));
+    if ubx_rate.is_some_and(|rate| rate == 0 || rate > ubx::MAX_RATE_HZ) {
+        println!("--ubx must be from 1 to {} hz", ubx::MAX_RATE_HZ);
+        process::exit(1);
+    }
+
+    // serial ports to search for the gps instead of the usual path and every usb serial port
+    let configured_ports: Vec<String> = take_flag(&mut args, "--port", "a serial port path")
+        .map(|ports| ports.split(',').map(|p| p.trim().to_string()).collect())
+        .unwrap_or_default();
+
+    if args.len() > 1 {
+        let args: Vec<&str> = args[1..].iter().map(|a| a.as_str()).collect();
+        if let ["shm-read", unit] = args.as_slice() {
+            if let Err(e) = clock::read(parse_number(unit, "SHM unit")) {
+                println!("{e}");
+            }
+        } else {
+            println!("Usage: ev-gps [--port <path,...>] [--shm <unit>] [--gpsd <port>] [--smooth] [--ubx <hz>] | shm-read <unit>");
+        }
+        process::exit(1);
+    }
+
+    let refclock = shm_unit.and_then(|unit| ShmRefclock::open(unit).map_err(|e| println!("{e}, not feeding the system clock")).ok());
+    let mut clock = Clock::new(refclock);
+    let mut gpsd = gpsd_port.and_then(|port| GpsdServer::start(port).map_err(|e| println!("{e}, not serving gpsd json")).ok());
+
+    let mut gps_port: Option<Box<dyn SerialPort>> = None; // none while the gps is being searched for
+    let mut search: Option<Search> = None; // ports left to probe in the current search
+    let mut last_port: Option<(String, u32)> = None; // port and baud rate that last worked, tried first
+    let mut retry = MIN_RETRY;
+    let mut next_search = Instant::now();
+
+    let mut serial_buffer = [0;READ_LEN]; // buffer to store bytes as they are received
 
     let mut parser = NmeaParser::new(); // nmea 0183 parser
     let mqtt_client = open_mqtt_connection();
 
-    let mut i = 0; // buffer index
+    let mut framer = Framer::default();
+    let mut quality = FixQuality::default();
+    let mut smoother = smooth.then(Smoother::default);
+    let mut last_stats = Instant::now();
+    let mut last_pvt: Option<Instant> = None; // when NAV-PVT last arrived, none without --ubx or when the receiver doesn't send it
+
+    let mut odometer = Odometer::load();
+    let mut geofences = Geofences::load();
+    let mut location: Option<String> = None; // last published location name
+    let rx = mqtt_client.start_consuming();
+    subscribe_trip_topics(&mqtt_client);
+    let mut resubscribe = false;
+    write_mqtt_retained(&mqtt_client, GPS_STATUS_TOPIC, "offline"); //offline until the gps is found
+
     loop{
-        if reconnect {
-            gps_port = connect_gps();
-            reconnect = false;
-        }
-        match gps_port.read(&mut serial_buffer){ // read new byte from serial port
-                Ok(_) => {
-                    let mut increment = false; // reset increment boolean to false
-
-                    // check if buffer is full
-                    if i == MSG_LEN + 1{
-                        i = 0; // reset back to first index
-                        message_buffer = [0;MSG_LEN]; // reset buffer to zeros
+        if gps_port.is_none() && Instant::now() >= next_search {
+            let last = last_port.as_ref().map(|(path, baud_rate)| (path.as_str(), *baud_rate));
+            let current = search.get_or_insert_with(|| Search::new(&configured_ports, last));
+
+            // one port and baud rate per pass so trip messages are handled between probes
+            match current.probe_next() {
+                Some(gps) => {
+                    let gps = configure_gps(gps, ubx_rate);
+                    write_mqtt_message(&mqtt_client, "gps", format!("gps,device=gps connected=1,baud_rate={}", gps.baud_rate).as_str()); //influxdb protocol
+                    write_mqtt_retained(&mqtt_client, GPS_STATUS_TOPIC, "online"); //live data for dashboard
+                    if let Some(gpsd) = gpsd.as_mut() {
+                        gpsd.set_device(&gps.path, gps.baud_rate);
                     }
-                    
-                    // if first byte in message
-                    if i == 0 {
-                        // check if byte is $
-                        if serial_buffer[0] == 0x24 {
-                            message_buffer[i] = serial_buffer[0]; // add current byte to buffer
-                            increment = true; // allow loop to incrememnt index
+
+                    // a receiver configured for ubx comes back at the new baud rate until it is power cycled
+                    last_port = Some((gps.path, gps.baud_rate));
+                    gps_port = Some(gps.port);
+                    framer.reset();
+                    search = None;
+                    retry = MIN_RETRY;
+                }
+                None if current.is_done() => {
+                    println!("No GPS found, searching again in {}s", retry.as_secs());
+                    next_search = Instant::now() + retry;
+                    retry = (retry * 2).min(MAX_RETRY);
+                    search = None;
+                }
+                None => {}
+            }
+        }
+        // trip resets and ignition from the motor controller
+        for msg in rx.try_iter() {
+            match msg {
+                Some(msg) => handle_trip_message(&mqtt_client, &mut odometer, msg.topic(), msg.payload_str().trim()),
+                None => resubscribe = true, // lost connection, subscriptions are gone once it reconnects
+            }
+        }
+        if resubscribe && mqtt_client.is_connected() {
+            subscribe_trip_topics(&mqtt_client);
+            resubscribe = false;
+        }
+
+        // keep handling trip messages while there is no gps
+        let Some(port) = gps_port.as_mut() else {
+            thread::sleep(MIN_RETRY.min(next_search.saturating_duration_since(Instant::now())));
+            continue;
+        };
+
+        match port.read(&mut serial_buffer){ // read available bytes from serial port
+            Ok(len) => {
+                let received = Utc::now(); // system time for the clock offset, later than the gps time by the serial latency
+                for packet in framer.push(&serial_buffer[..len]) {
+                    let sentence = match packet {
+                        Packet::Nmea(sentence) => sentence,
+                        Packet::Ubx(frame) => {
+                            if let Some(pvt) = frame.parse_pvt() {
+                                last_pvt = Some(Instant::now());
+                                quality.update_pvt(&pvt);
+                                publish_pvt(&mqtt_client, &pvt);
+
+                                clock.set_valid(pvt.time.is_some());
+                                if let Some(time) = pvt.time {
+                                    update_clock(&mqtt_client, &mut clock, time, received);
+                                }
+                            }
+                            continue;
                         }
+                    };
+                    //println!("{sentence}");
+                    if let Some(gpsd) = gpsd.as_mut() {
+                        gpsd.nmea(&sentence);
                     }
-                    else {
-                        message_buffer[i] = serial_buffer[0]; // add current byte to buffer
-                        increment = true; // allow loop to incrememnt index
+                    if let Some(zda) = sentences::parse_zda(&sentence) {
+                        publish_zda(&mqtt_client, &zda);
+                        update_clock(&mqtt_client, &mut clock, zda.time, received);
+                    } else if let Some(gst) = sentences::parse_gst(&sentence) {
+                        publish_gst(&mqtt_client, &gst);
+                        if let Some(gpsd) = gpsd.as_mut() {
+                            gpsd.update_gst(&gst);
+                        }
+                    } else if let Ok(mut sentence) = parser.parse_sentence(&sentence) {
+                        quality.update(&sentence);
+                        if let Some(gpsd) = gpsd.as_mut() {
+                            gpsd.update(&sentence);
+                        }
+
+                        if let ParsedMessage::Rmc(rmc) = &mut sentence {
+                            // positions from poor fixes are dropped so they don't spike maps, distance and geofences
+                            if check_fix(&mqtt_client, &mut quality, rmc) {
+                                update_odometer(&mqtt_client, &mut odometer, rmc);
+                                update_geofences(&mqtt_client, &mut geofences, &mut location, rmc);
+                            }
+
+                            // the clock gets the more precise NAV-PVT time instead while it is arriving
+                            if last_pvt.is_none_or(|time| time.elapsed() > PVT_TIMEOUT) {
+                                clock.set_valid(rmc.status_active == Some(true));
+                                if let Some(time) = rmc.timestamp {
+                                    update_clock(&mqtt_client, &mut clock, time, received);
+                                }
+                            }
+
+                            if let Some(smoother) = smoother.as_mut() {
+                                smoother.smooth(rmc, quality.hdop());
+                            }
+                        }
+                        publish_sentence(&mqtt_client, sentence, quality.fix_ok());
                     }
+                }
+
+                if last_stats.elapsed() >= FRAMER_STATS_RATE {
+                    write_mqtt_message(&mqtt_client, "gps", format!("gps,device=gps {},{}", framer.stats.fields(), quality.fields()).as_str()); //influxdb protocol
+                    write_mqtt_message(&mqtt_client, "live/gps/sentences_rejected", format!("{}", framer.stats.rejected()).as_str()); //live data for dashboard
+                    last_stats = Instant::now();
+                }
+            },
+            Err(e) => {
+                eprintln!("Read Error:{:?}", e);
+                write_mqtt_message(&mqtt_client, "gps", "gps,device=gps connected=0"); //influxdb protocol
+                write_mqtt_retained(&mqtt_client, GPS_STATUS_TOPIC, "offline"); //live data for dashboard
+                if let Some(gpsd) = gpsd.as_mut() {
+                    gpsd.clear_device();
+                }
+                gps_port = None;
+                next_search = Instant::now();
+            }
+        }
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str, value: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == flag)?;
+    let Some(v) = args.get(i + 1).cloned() else {
+        println!("{flag} requires {value}");
+        process::exit(1);
+    };
+    args.drain(i..i + 2);
+
+    Some(v)
+}
+
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    let Some(i) = args.iter().position(|a| a == flag) else { return false };
+    args.remove(i);
+    true
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, name: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        println!("Invalid {name} {value}");
+        process::exit(1);
+    })
+}
+
+// feed gps time to the refclock and publish how far the system clock is from it
+fn update_clock(mqtt_client: &Client, clock: &mut Clock, gps_time: DateTime<Utc>, received: DateTime<Utc>) {
+    let Some(clock_offset_ms) = clock.sample(gps_time, received) else { return };
+
+    write_mqtt_message(mqtt_client, "gps", format!("gps,device=gps clock_offset_ms={clock_offset_ms}").as_str()); //influxdb protocol
+    write_mqtt_message(mqtt_client, "live/gps/clock_offset", format!("{clock_offset_ms}").as_str()); //live data for dashboard
+}
+
+fn subscribe_trip_topics(mqtt_client: &Client) {
+    for topic in [TRIP_RESET_TOPIC, CONTROLLER_STATUS_TOPIC, CONTROLLER_FLAG_TOPIC] {
+        if let Err(e) = mqtt_client.subscribe(topic, 1) {
+            println!("Error subscribing to {topic}: {:?}", e);
+        }
+    }
+}
 
-                    if i > 0 && message_buffer[i-1] == 0x0d && message_buffer[i] == 0x0a {
-                        let sentence = str::from_utf8(&message_buffer).unwrap();
-                        //println!("{sentence}");
+fn handle_trip_message(mqtt_client: &Client, odometer: &mut Odometer, topic: &str, payload: &str) {
+    let event = match (topic, payload) {
+        (TRIP_RESET_TOPIC, meter) => {
+            if odometer.reset(meter) {
+                println!("Trip {meter} reset");
+                publish_trip_meters(mqtt_client, odometer);
+            } else {
+                println!("Unknown trip meter {meter}, expected a or b");
+            }
+            None
+        }
+        // the controller only answers with the key on, so coming online is the key turning on
+        (CONTROLLER_STATUS_TOPIC, "online") => odometer.set_ignition(true),
+        (CONTROLLER_STATUS_TOPIC, "offline") => odometer.set_ignition(false),
+        // only sent when the key_on bit is named in the ev-alltrax flag map
+        (CONTROLLER_FLAG_TOPIC, "raised,key_on") => odometer.set_ignition(true),
+        (CONTROLLER_FLAG_TOPIC, "cleared,key_on") => odometer.set_ignition(false),
+        _ => None,
+    };
+
+    publish_trip_event(mqtt_client, event);
+}
 
-                        if let Ok(sentence) = parser.parse_sentence(sentence) {
-                            match sentence {
-                                ///// RMC /////
-                                ParsedMessage::Rmc(rmc) => {
-                                    if (rmc.latitude.is_some() && rmc.longitude.is_some()) || rmc.sog_knots.is_some() || rmc.bearing.is_some() {
-                                        let mut payload: String = "gps,device=gps ".to_string();
+// gate an rmc fix on the fix quality, publishing ok or the reason it was rejected
+fn check_fix(mqtt_client: &Client, quality: &mut FixQuality, rmc: &RmcData) -> bool {
+    let result = quality.gate(rmc.status_active == Some(true));
 
-                                        if rmc.latitude.is_some() && rmc.longitude.is_some(){
-                                            let lat = rmc.latitude.unwrap();
-                                            let lon = rmc.longitude.unwrap();
+    write_mqtt_message(mqtt_client, "live/gps/fix_quality", result.err().unwrap_or("ok")); //live data for dashboard
+    result.is_ok()
+}
 
-                                            payload.push_str(format!("latitude={lat:.8},longitude={lon:.8}").as_str());
-                                            write_mqtt_message(&mqtt_client, "live/gps/position", format!("{lat:.8},{lon:.8}").as_str()); //live data for dashboard
-                                        }
+// count distance from good fixes
+fn update_odometer(mqtt_client: &Client, odometer: &mut Odometer, rmc: &RmcData) {
+    let (Some(lat), Some(lon)) = (rmc.latitude, rmc.longitude) else { return };
+    let speed = rmc.sog_knots.unwrap_or(0.0) * 1.852;
 
-                                        if rmc.sog_knots.is_some() {
-                                            let speed = rmc.sog_knots.unwrap() * 1.852;
+    let event = odometer.update_fix(lat, lon, speed);
+    publish_trip_event(mqtt_client, event);
+    publish_trip_meters(mqtt_client, odometer);
+}
 
-                                            payload.push_str(format!(",speed={speed:.1}").as_str());
-                                            write_mqtt_message(&mqtt_client, "live/gps/speed", format!("{speed:.1}").as_str()); //live data for dashboard
-                                        }
+// enter and exit events from good fixes, and the retained name of the current location
+fn update_geofences(mqtt_client: &Client, geofences: &mut Geofences, location: &mut Option<String>, rmc: &RmcData) {
+    let (Some(lat), Some(lon)) = (rmc.latitude, rmc.longitude) else { return };
 
-                                        if rmc.bearing.is_some() {
-                                            let bearing = rmc.bearing.unwrap();
+    for event in geofences.update(lat, lon) {
+        let action = if event.entered { "enter" } else { "exit" };
+        println!("Geofence {action} {}", event.name);
 
-                                            payload.push_str(format!(",bearing={bearing:.1}").as_str());
-                                            write_mqtt_message(&mqtt_client, "live/gps/bearing", format!("{bearing:.1}").as_str()); //live data for dashboard
-                                        }
+        write_mqtt_message(mqtt_client, "gps", format!("geofence,device=gps,name={} inside={}", event.name, event.entered as i32).as_str()); //influxdb protocol
+        write_mqtt_message(mqtt_client, "live/gps/geofence_event", format!("{action},{}", event.name).as_str()); //live data for dashboard
+    }
 
-                                        write_mqtt_message(&mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
-                                    }
-                                },
+    // also on the first fix, replacing the location retained from before a restart
+    let name = geofences.location_name();
+    if location.as_deref() != Some(name) {
+        write_mqtt_retained(mqtt_client, "live/gps/location_name", name); //live data for dashboard
+        *location = Some(name.to_string());
+    }
+}
 
-                                ///// GGA /////
-                                ParsedMessage::Gga(gga) => {
-                                    if gga.satellite_count.is_some() || gga.hdop.is_some() || gga.altitude.is_some() {
-                                        let mut payload: String = "gps,device=gps ".to_string();
+fn publish_trip_event(mqtt_client: &Client, event: Option<TripEvent>) {
+    match event {
+        Some(TripEvent::Started) => {
+            println!("Trip started");
+            write_mqtt_retained(mqtt_client, "live/trip/active", "1"); //live data for dashboard
+        }
+        Some(TripEvent::Ended(summary)) => {
+            println!("Trip ended: {summary}");
+            write_mqtt_message(mqtt_client, "gps", summary.as_str()); //influxdb protocol
+            write_mqtt_retained(mqtt_client, "live/trip/active", "0"); //live data for dashboard
+        }
+        None => {}
+    }
+}
 
-                                        if gga.altitude.is_some() {
-                                            let altitude = gga.altitude.unwrap();
+fn publish_trip_meters(mqtt_client: &Client, odometer: &Odometer) {
+    write_mqtt_message(mqtt_client, "live/trip/odometer", format!("{:.1}", odometer.total_m / 1000.0).as_str()); //live data for dashboard
+    write_mqtt_message(mqtt_client, "live/trip/a", format!("{:.1}", odometer.trip_a_m / 1000.0).as_str()); //live data for dashboard
+    write_mqtt_message(mqtt_client, "live/trip/b", format!("{:.1}", odometer.trip_b_m / 1000.0).as_str()); //live data for dashboard
+    if let Some(distance) = odometer.trip_distance_m() {
+        write_mqtt_message(mqtt_client, "live/trip/distance", format!("{:.2}", distance / 1000.0).as_str()); //live data for dashboard
+    }
+}
 
-                                            payload.push_str(format!("altitude={altitude:.1}").as_str());
-                                            write_mqtt_message(&mqtt_client, "live/gps/altitude", format!("{altitude:.1}").as_str()); //live data for dashboard
-                                        }
+// fix_ok false leaves out the position, speed and bearing from rmc and the altitude from gga
+fn publish_sentence(mqtt_client: &Client, sentence: ParsedMessage, fix_ok: bool) {
+    match sentence {
+        ///// RMC /////
+        ParsedMessage::Rmc(rmc) if fix_ok => {
+            if (rmc.latitude.is_some() && rmc.longitude.is_some()) || rmc.sog_knots.is_some() || rmc.bearing.is_some() {
+                let mut payload: String = "gps,device=gps ".to_string();
+
+                if rmc.latitude.is_some() && rmc.longitude.is_some(){
+                    let lat = rmc.latitude.unwrap();
+                    let lon = rmc.longitude.unwrap();
+
+                    payload.push_str(format!("latitude={lat:.8},longitude={lon:.8}").as_str());
+                    if let Some(time) = rmc.timestamp {
+                        write_mqtt_message(mqtt_client, "live/gps/fix_time", time.to_rfc3339_opts(SecondsFormat::Millis, true).as_str()); //gps time of the position that follows, for ev-track
+                    }
+                    write_mqtt_message(mqtt_client, "live/gps/position", format!("{lat:.8},{lon:.8}").as_str()); //live data for dashboard
+                }
 
-                                        if gga.satellite_count.is_some() {
-                                            let satellite_count = gga.satellite_count.unwrap();
+                if rmc.sog_knots.is_some() {
+                    let speed = rmc.sog_knots.unwrap() * 1.852;
 
-                                            payload.push_str(format!(",satellite_count={satellite_count}").as_str());
-                                        }
+                    payload.push_str(format!(",speed={speed:.1}").as_str());
+                    write_mqtt_message(mqtt_client, "live/gps/speed", format!("{speed:.1}").as_str()); //live data for dashboard
+                }
 
-                                        /*if gga.hdop.is_some() {
-                                            let hdop = gga.hdop.unwrap();
+                if rmc.bearing.is_some() {
+                    let bearing = rmc.bearing.unwrap();
 
-                                            payload.push_str(format!(",hdop={hdop:.1}").as_str());
-                                        }*/
+                    payload.push_str(format!(",bearing={bearing:.1}").as_str());
+                    write_mqtt_message(mqtt_client, "live/gps/bearing", format!("{bearing:.1}").as_str()); //live data for dashboard
+                }
 
-                                        write_mqtt_message(&mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
-                                    }
-                                },
+                write_mqtt_message(mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
+            }
+        },
 
-                                ///// GSA /////
-                                ParsedMessage::Gsa(gsa) => {
-                                    if (gsa.mode1_automatic.is_some()) || gsa.mode2_3d.is_some() || gsa.pdop.is_some() || gsa.hdop.is_some() || gsa.vdop.is_some() {
-                                        let mut payload: String = "gps,device=gps ".to_string();
+        ///// GGA /////
+        ParsedMessage::Gga(gga) => {
+            let mut fields = Vec::new();
 
-                                        if gsa.mode1_automatic.is_some() {
-                                            let mode1_automatic = gsa.mode1_automatic.unwrap();
+            if let Some(altitude) = gga.altitude.filter(|_| fix_ok) {
+                fields.push(format!("altitude={altitude:.1}"));
+                write_mqtt_message(mqtt_client, "live/gps/altitude", format!("{altitude:.1}").as_str()); //live data for dashboard
+            }
 
-                                            payload.push_str(format!("mode1_automatic={}", mode1_automatic as i32).as_str());
-                                        }
+            if let Some(satellite_count) = gga.satellite_count {
+                fields.push(format!("satellite_count={satellite_count}"));
+            }
 
-                                        if gsa.mode2_3d.is_some() {
-                                            let mode2_3d = gsa.mode2_3d.unwrap();
+            /*if let Some(hdop) = gga.hdop {
+                fields.push(format!("hdop={hdop:.1}"));
+            }*/
 
-                                            payload.push_str(format!(",mode2_3d=\"{}\"", mode2_3d.to_string().replace(" ", "_")).as_str());
-                                        }
+            if !fields.is_empty() {
+                write_mqtt_message(mqtt_client, "gps", format!("gps,device=gps {}", fields.join(",")).as_str()); //influxdb protocol
+            }
+        },
 
-                                        if gsa.pdop.is_some() {
-                                            let pdop = gsa.pdop.unwrap();
+        ///// GSA /////
+        ParsedMessage::Gsa(gsa) => {
+            if (gsa.mode1_automatic.is_some()) || gsa.mode2_3d.is_some() || gsa.pdop.is_some() || gsa.hdop.is_some() || gsa.vdop.is_some() {
+                let mut payload: String = "gps,device=gps ".to_string();
 
-                                            payload.push_str(format!(",pdop={pdop:.1}").as_str());
-                                        }
+                if gsa.mode1_automatic.is_some() {
+                    let mode1_automatic = gsa.mode1_automatic.unwrap();
 
-                                        if gsa.hdop.is_some() {
-                                            let hdop = gsa.hdop.unwrap();
+                    payload.push_str(format!("mode1_automatic={}", mode1_automatic as i32).as_str());
+                }
 
-                                            payload.push_str(format!(",hdop={hdop:.1}").as_str());
-                                        }
+                if gsa.mode2_3d.is_some() {
+                    let mode2_3d = gsa.mode2_3d.unwrap();
 
-                                        if gsa.vdop.is_some() {
-                                            let vdop = gsa.vdop.unwrap();
+                    payload.push_str(format!(",mode2_3d=\"{}\"", mode2_3d.to_string().replace(" ", "_")).as_str());
+                }
 
-                                            payload.push_str(format!(",vdop={vdop:.1}").as_str());
-                                        }
+                if gsa.pdop.is_some() {
+                    let pdop = gsa.pdop.unwrap();
 
-                                        write_mqtt_message(&mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
-                                    }
-                                },
-                                
-                                ///// GLL /////
-                                ParsedMessage::Gll(gll) => {
-                                    if gll.data_valid.is_some() {
-                                        let mut payload: String = "gps,device=gps ".to_string();
+                    payload.push_str(format!(",pdop={pdop:.1}").as_str());
+                }
 
-                                        if gll.data_valid.is_some() {
-                                            let data_valid = gll.data_valid.unwrap();
+                if gsa.hdop.is_some() {
+                    let hdop = gsa.hdop.unwrap();
 
-                                            payload.push_str(format!("data_valid={}", data_valid as i32).as_str());
-                                        }
+                    payload.push_str(format!(",hdop={hdop:.1}").as_str());
+                }
 
-                                        write_mqtt_message(&mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
-                                    }
-                                },
-                                _ => {
-                                }
-                            }
-                        }
-                        
-                        message_buffer = [0;MSG_LEN];
-                        i = 0;
+                if gsa.vdop.is_some() {
+                    let vdop = gsa.vdop.unwrap();
 
-                        increment = false;
-                    }
+                    payload.push_str(format!(",vdop={vdop:.1}").as_str());
+                }
 
-                    if increment {
-                        i += 1;
+                write_mqtt_message(mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
+            }
+        },
+    
+        ///// GLL /////
+        ParsedMessage::Gll(gll) => {
+            if gll.data_valid.is_some() {
+                let mut payload: String = "gps,device=gps ".to_string();
+
+                if gll.data_valid.is_some() {
+                    let data_valid = gll.data_valid.unwrap();
+
+                    payload.push_str(format!("data_valid={}", data_valid as i32).as_str());
+                }
+
+                write_mqtt_message(mqtt_client, "gps", payload.as_str().clone()); //influxdb protocol
+            }
+        },
+
+        ///// VTG /////
+        ParsedMessage::Vtg(vtg) => {
+            let mut fields = Vec::new();
+
+            if let Some(track_true) = vtg.cog_true {
+                fields.push(format!("track_true={track_true:.1}"));
+            }
+
+            if let Some(track_magnetic) = vtg.cog_magnetic {
+                fields.push(format!("track_magnetic={track_magnetic:.1}"));
+            }
+
+            if let Some(speed) = vtg.sog_kph.or(vtg.sog_knots.map(|knots| knots * 1.852)) {
+                fields.push(format!("vtg_speed={speed:.1}"));
+            }
+
+            if !fields.is_empty() {
+                write_mqtt_message(mqtt_client, "gps", format!("gps,device=gps {}", fields.join(",")).as_str()); //influxdb protocol
+            }
+        },
+
+        ///// GSV /////
+        ParsedMessage::Gsv(satellites) => {
+            if let Some(first) = satellites.first() {
+                let system = format!("{:?}", first.source).to_lowercase();
+
+                // one record per satellite, snr is missing for satellites in view but not tracked
+                let mut lines = Vec::new();
+                let mut sky = Vec::new();
+                for sat in satellites.iter() {
+                    let mut fields = Vec::new();
+                    if let Some(elevation) = sat.elevation {
+                        fields.push(format!("elevation={elevation}"));
+                    }
+                    if let Some(azimuth) = sat.azimuth {
+                        fields.push(format!("azimuth={azimuth}"));
+                    }
+                    if let Some(snr) = sat.snr {
+                        fields.push(format!("snr={snr}"));
+                    }
+                    if !fields.is_empty() {
+                        lines.push(format!("gps_satellite,device=gps,system={system},prn={} {}", sat.prn_number, fields.join(",")));
                     }
-                },
-                Err(e) => {
-                    eprintln!("Read Error:{:?}", e);
-                    reconnect = true;
+
+                    let json = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+                    sky.push(format!(
+                        "{{\"prn\":{},\"elevation\":{},\"azimuth\":{},\"snr\":{}}}",
+                        sat.prn_number,
+                        json(sat.elevation.map(|v| v.to_string())),
+                        json(sat.azimuth.map(|v| v.to_string())),
+                        json(sat.snr.map(|v| v.to_string()))
+                    ));
+                }
+
+                let snrs: Vec<u8> = satellites.iter().filter_map(|sat| sat.snr).collect();
+                let mut summary = format!("gps,device=gps,system={system} satellites_in_view={},satellites_tracked={}", satellites.len(), snrs.len());
+                if !snrs.is_empty() {
+                    let snr_mean = snrs.iter().map(|snr| *snr as f64).sum::<f64>() / snrs.len() as f64;
+                    summary.push_str(format!(",snr_mean={snr_mean:.1},snr_max={}", snrs.iter().max().unwrap_or(&0)).as_str());
                 }
+                lines.push(summary);
+
+                write_mqtt_message(mqtt_client, "gps", lines.join("\n").as_str()); //influxdb protocol
+                write_mqtt_message(mqtt_client, format!("live/gps/satellites/{system}").as_str(), format!("[{}]", sky.join(",")).as_str()); //live data for dashboard sky plot
+            }
+        },
+        _ => {
         }
     }
 }
 
+// utc date and time
+fn publish_zda(mqtt_client: &Client, zda: &Zda) {
+    let payload = format!("gps,device=gps utc_zone_minutes={}", zda.zone_minutes);
+    write_mqtt_message(mqtt_client, "gps", payload.as_str()); //influxdb protocol
+    write_mqtt_message(mqtt_client, "live/gps/time", zda.time.to_rfc3339_opts(SecondsFormat::Millis, true).as_str()); //live data for dashboard
+}
+
+// navigation solution accuracy and fix flags from a u-blox receiver
+fn publish_pvt(mqtt_client: &Client, pvt: &Pvt) {
+    write_mqtt_message(mqtt_client, "gps", format!("gps,device=gps {}", pvt.fields()).as_str()); //influxdb protocol
+    write_mqtt_message(mqtt_client, "live/gps/position_error", format!("{:.1}", pvt.horizontal_accuracy).as_str()); //live data for dashboard
+    write_mqtt_message(mqtt_client, "live/gps/speed_accuracy", format!("{:.1}", pvt.speed_accuracy).as_str()); //live data for dashboard
+}
+
+// pseudorange error estimates in meters
+fn publish_gst(mqtt_client: &Client, gst: &Gst) {
+    let values = [
+        ("rms_range_error", gst.rms),
+        ("error_major", gst.error_major),
+        ("error_minor", gst.error_minor),
+        ("error_orientation", gst.error_orientation),
+        ("lat_error", gst.lat_error),
+        ("lon_error", gst.lon_error),
+        ("alt_error", gst.alt_error),
+    ];
+    let fields: Vec<String> = values.iter().filter_map(|(name, value)| value.map(|v| format!("{name}={v:.2}"))).collect();
+    if fields.is_empty() {
+        return;
+    }
+
+    write_mqtt_message(mqtt_client, "gps", format!("gps,device=gps {}", fields.join(",")).as_str()); //influxdb protocol
+    if let Some(error) = gst.horizontal_error() {
+        write_mqtt_message(mqtt_client, "live/gps/position_error", format!("{error:.1}").as_str()); //live data for dashboard
+    }
+}
+
 fn write_mqtt_message(mqtt_client: &Client, topic: &str, payload: &str){
     let msg = mqtt::Message::new(topic, payload.clone(), 1); //build message
+    publish_mqtt_message(mqtt_client, msg);
+}
+
+fn write_mqtt_retained(mqtt_client: &Client, topic: &str, payload: &str){
+    let msg = mqtt::Message::new_retained(topic, payload, 1); //build message
+    publish_mqtt_message(mqtt_client, msg);
+}
+
+fn publish_mqtt_message(mqtt_client: &Client, msg: mqtt::Message){
     if !mqtt_client.is_connected(){ //check if connected to broker
         println!("Lost connection to mqtt broker");
         match mqtt_client.reconnect(){ //reconnext to broker
@@ -244,10 +637,15 @@ fn open_mqtt_connection() -> Client {
     tok.unwrap();
 }*/
 
-fn connect_gps() -> Box<dyn SerialPort>{
-    let gps_port = serialport::new(GPS_PATH, GPS_BAUD_RATE)
-        .timeout(Duration::from_millis(10000))
-        .open()
-        .expect("Failed to open serial port {GPS_PATH}");
-    return gps_port;
+// settings aren't saved on the receiver, so configure on every connect
+fn configure_gps(mut gps: GpsPort, ubx_rate: Option<u16>) -> GpsPort {
+    println!("Found GPS on {} at {} baud", gps.path, gps.baud_rate);
+
+    if let Some(rate) = ubx_rate {
+        match ubx::configure(&mut gps.port, rate) {
+            Ok(_) => gps.baud_rate = ubx::UBX_BAUD_RATE,
+            Err(e) => println!("{e}, continuing at {} baud", gps.baud_rate),
+        }
+    }
+    gps
 }
\ No newline at end of file
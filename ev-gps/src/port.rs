@@ -0,0 +1,105 @@
+// finds the gps among serial ports and baud rates by listening for valid nmea or ubx
+use std::fs;
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, SerialPortType};
+
+use crate::framer::Framer;
+
+const GPS_PATH: &str = "/dev/ttyGPS"; // udev symlink to the usb gps, tried first
+const OTHER_DEVICES: [&str; 1] = ["/dev/ttySOLAR"]; // ports of other services, never probed
+const BAUD_RATES: [u32; 6] = [9600, 115200, 4800, 38400, 19200, 57600]; // most common first, 115200 after ubx configuration
+const PROBE_TIME: Duration = Duration::from_millis(2500); // long enough for a 1 hz receiver to send a few sentences
+const PROBE_READ_TIMEOUT: Duration = Duration::from_millis(200);
+const MIN_VALID: u32 = 2; // valid sentences or frames to accept a port, one could be chance at the wrong baud
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10); // no data for this long means the gps is gone
+
+pub struct GpsPort {
+    pub port: Box<dyn SerialPort>,
+    pub path: String,
+    pub baud_rate: u32,
+}
+
+// ports and baud rates left to try, probed one at a time so the main loop keeps running between them
+pub struct Search {
+    attempts: Vec<(String, u32)>, // in reverse, the next is last
+}
+
+// configured paths, or the usual path then every usb serial port
+fn candidates(configured: &[String]) -> Vec<String> {
+    if !configured.is_empty() {
+        return configured.to_vec();
+    }
+
+    let others: Vec<_> = OTHER_DEVICES.iter().filter_map(|path| fs::canonicalize(path).ok()).collect();
+    let mut paths = vec![GPS_PATH.to_string()];
+
+    for info in serialport::available_ports().unwrap_or_default() {
+        let is_usb = matches!(info.port_type, SerialPortType::UsbPort(_));
+        let is_other = fs::canonicalize(&info.port_name).is_ok_and(|path| others.contains(&path));
+
+        if is_usb && !is_other && !paths.contains(&info.port_name) {
+            paths.push(info.port_name);
+        }
+    }
+
+    paths
+}
+
+// listen on a port at one baud rate for valid nmea sentences or ubx frames
+fn probe(path: &str, baud_rate: u32) -> Option<Box<dyn SerialPort>> {
+    let mut port = serialport::new(path, baud_rate).timeout(PROBE_READ_TIMEOUT).open().ok()?;
+    let mut framer = Framer::default();
+    let mut buf = [0u8; 256];
+    let start = Instant::now();
+
+    while start.elapsed() < PROBE_TIME {
+        match port.read(&mut buf) {
+            Ok(len) => {
+                framer.push(&buf[..len]);
+                if framer.stats.ok + framer.stats.ubx_ok >= MIN_VALID {
+                    port.set_timeout(READ_TIMEOUT).ok()?;
+                    return Some(port);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(_) => return None,
+        }
+    }
+
+    None
+}
+
+impl Search {
+    // the last port that worked first, then every candidate at every baud rate
+    pub fn new(configured: &[String], last: Option<(&str, u32)>) -> Search {
+        let mut attempts = Vec::new();
+        if let Some((path, baud_rate)) = last {
+            attempts.push((path.to_string(), baud_rate));
+        }
+
+        for path in candidates(configured) {
+            // skip paths that don't exist rather than trying each baud rate
+            if fs::metadata(&path).is_err() {
+                continue;
+            }
+            for baud_rate in BAUD_RATES {
+                attempts.push((path.clone(), baud_rate));
+            }
+        }
+
+        attempts.reverse();
+        Search { attempts }
+    }
+
+    // probe the next port and baud rate, blocking for up to PROBE_TIME
+    pub fn probe_next(&mut self) -> Option<GpsPort> {
+        let (path, baud_rate) = self.attempts.pop()?;
+        let port = probe(&path, baud_rate)?;
+        Some(GpsPort { port, path, baud_rate })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.attempts.is_empty()
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use nmea_parser::gnss::{GgaQualityIndicator, GsaFixMode};
+use nmea_parser::ParsedMessage;
+
+use crate::ubx::Pvt;
+
+const MAX_AGE: Duration = Duration::from_secs(3); // older values are ignored, e.g. when the receiver stops sending GSA
+const MIN_SATELLITES: u8 = 4; // fewer can't give a 3d position
+const MAX_HDOP: f64 = 5.0; // above this the position wanders by tens of meters
+const MAX_PDOP: f64 = 10.0;
+const MAX_HORIZONTAL_ACCURACY: f64 = 25.0; // m, estimated accuracy from NAV-PVT
+
+// latest value and when it was received
+type Reading<T> = Option<(T, Instant)>;
+
+fn fresh<T: Copy>(reading: Reading<T>) -> Option<T> {
+    reading.filter(|(_, time)| time.elapsed() <= MAX_AGE).map(|(value, _)| value)
+}
+
+// fix quality from the latest GGA, GSA and GLL, used to gate the position from RMC
+#[derive(Default)]
+pub struct FixQuality {
+    gga_fix: Reading<bool>, // gga quality is anything but invalid
+    satellites: Reading<u8>,
+    gsa_mode: Reading<u8>, // 1 no fix, 2 2d, 3 3d
+    hdop: Reading<f64>,
+    pdop: Reading<f64>,
+    gll_valid: Reading<bool>,
+    pvt_fix: Reading<bool>, // u-blox fix type and gnss fix ok flag
+    horizontal_accuracy: Reading<f64>,
+    fix_ok: bool, // result of the last gate
+    pub ok: u32,
+    pub rejected: u32,
+}
+
+impl FixQuality {
+    pub fn update(&mut self, sentence: &ParsedMessage) {
+        let now = Instant::now();
+
+        match sentence {
+            ParsedMessage::Gga(gga) => {
+                self.gga_fix = Some((!matches!(gga.quality, GgaQualityIndicator::Invalid), now));
+                self.satellites = gga.satellite_count.map(|count| (count, now)).or(self.satellites);
+                self.hdop = gga.hdop.map(|hdop| (hdop, now)).or(self.hdop);
+            }
+            ParsedMessage::Gsa(gsa) => {
+                let mode = gsa.mode2_3d.as_ref().map(|mode| match mode {
+                    GsaFixMode::NotAvailable => 1,
+                    GsaFixMode::Fix2D => 2,
+                    GsaFixMode::Fix3D => 3,
+                });
+                self.gsa_mode = mode.map(|mode| (mode, now)).or(self.gsa_mode);
+                self.hdop = gsa.hdop.map(|hdop| (hdop, now)).or(self.hdop);
+                self.pdop = gsa.pdop.map(|pdop| (pdop, now)).or(self.pdop);
+            }
+            ParsedMessage::Gll(gll) => {
+                self.gll_valid = gll.data_valid.map(|valid| (valid, now)).or(self.gll_valid);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn update_pvt(&mut self, pvt: &Pvt) {
+        let now = Instant::now();
+        self.pvt_fix = Some((pvt.position_ok(), now));
+        self.horizontal_accuracy = Some((pvt.horizontal_accuracy, now));
+    }
+
+    // check a fix from rmc, giving the reason when it is rejected, values a receiver doesn't send don't count against it
+    pub fn gate(&mut self, rmc_active: bool) -> Result<(), &'static str> {
+        let result = if !rmc_active || fresh(self.gga_fix) == Some(false) || fresh(self.gsa_mode) == Some(1) || fresh(self.pvt_fix) == Some(false) {
+            Err("no_fix")
+        } else if fresh(self.gll_valid) == Some(false) {
+            Err("data_invalid")
+        } else if fresh(self.satellites).is_some_and(|count| count < MIN_SATELLITES) {
+            Err("few_satellites")
+        } else if fresh(self.hdop).is_some_and(|hdop| hdop > MAX_HDOP) {
+            Err("high_hdop")
+        } else if fresh(self.pdop).is_some_and(|pdop| pdop > MAX_PDOP) {
+            Err("high_pdop")
+        } else if fresh(self.horizontal_accuracy).is_some_and(|accuracy| accuracy > MAX_HORIZONTAL_ACCURACY) {
+            Err("low_accuracy")
+        } else {
+            Ok(())
+        };
+
+        self.fix_ok = result.is_ok();
+        if self.fix_ok {
+            self.ok += 1;
+        } else {
+            self.rejected += 1;
+        }
+        result
+    }
+
+    pub fn fix_ok(&self) -> bool {
+        self.fix_ok
+    }
+
+    pub fn hdop(&self) -> Option<f64> {
+        fresh(self.hdop)
+    }
+
+    // influxdb fields
+    pub fn fields(&self) -> String {
+        format!("fixes_ok={},fixes_rejected={}", self.ok, self.rejected)
+    }
+}
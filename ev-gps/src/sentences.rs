@@ -0,0 +1,86 @@
+// sentences nmea_parser does not decode, parsed by hand
+use nmea_parser::chrono::{DateTime, NaiveDate, Utc};
+
+// ZDA, utc date and time
+pub struct Zda {
+    pub time: DateTime<Utc>,
+    pub zone_minutes: i32, // local zone offset from utc, usually 0
+}
+
+// GST, pseudorange error statistics in meters
+pub struct Gst {
+    pub rms: Option<f64>, // rms of the pseudorange residuals
+    pub error_major: Option<f64>,
+    pub error_minor: Option<f64>,
+    pub error_orientation: Option<f64>, // degrees from true north
+    pub lat_error: Option<f64>,
+    pub lon_error: Option<f64>,
+    pub alt_error: Option<f64>,
+}
+
+// fields after the address, without the checksum, if the sentence type matches e.g. "ZDA" for $GPZDA or $GNZDA
+fn fields<'a>(sentence: &'a str, sentence_type: &str) -> Option<Vec<&'a str>> {
+    let body = sentence.strip_prefix('$')?;
+    let body = body.split_once('*').map_or(body, |(body, _)| body);
+    let mut fields = body.split(',');
+
+    let address = fields.next()?;
+    if address.len() != 5 || !address.ends_with(sentence_type) {
+        return None;
+    }
+
+    Some(fields.collect())
+}
+
+fn number(field: Option<&&str>) -> Option<f64> {
+    field.and_then(|f| f.parse().ok())
+}
+
+// $GPZDA,hhmmss.ss,dd,mm,yyyy,zh,zm
+pub fn parse_zda(sentence: &str) -> Option<Zda> {
+    let f = fields(sentence, "ZDA")?;
+    let time = f.first()?;
+    if time.len() < 6 {
+        return None;
+    }
+
+    let hour = time[0..2].parse().ok()?;
+    let minute = time[2..4].parse().ok()?;
+    let second: f64 = time[4..].parse().ok()?;
+    let day = f.get(1)?.parse().ok()?;
+    let month = f.get(2)?.parse().ok()?;
+    let year = f.get(3)?.parse().ok()?;
+
+    let time = NaiveDate::from_ymd_opt(year, month, day)?
+        .and_hms_milli_opt(hour, minute, second.trunc() as u32, (second.fract() * 1000.0).round() as u32)?
+        .and_utc();
+
+    // zone minutes take the sign of the hours
+    let zone_hours: i32 = f.get(4).and_then(|z| z.parse().ok()).unwrap_or(0);
+    let zone_minutes: i32 = f.get(5).and_then(|z| z.parse().ok()).unwrap_or(0);
+    let zone_minutes = zone_hours * 60 + if zone_hours < 0 { -zone_minutes } else { zone_minutes };
+
+    Some(Zda { time, zone_minutes })
+}
+
+// $GPGST,hhmmss.ss,rms,major,minor,orientation,lat_error,lon_error,alt_error
+pub fn parse_gst(sentence: &str) -> Option<Gst> {
+    let f = fields(sentence, "GST")?;
+
+    Some(Gst {
+        rms: number(f.get(1)),
+        error_major: number(f.get(2)),
+        error_minor: number(f.get(3)),
+        error_orientation: number(f.get(4)),
+        lat_error: number(f.get(5)),
+        lon_error: number(f.get(6)),
+        alt_error: number(f.get(7)),
+    })
+}
+
+impl Gst {
+    // horizontal position error, combining latitude and longitude errors
+    pub fn horizontal_error(&self) -> Option<f64> {
+        Some(self.lat_error?.hypot(self.lon_error?))
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Instant;
+
+use nmea_parser::gnss::RmcData;
+
+const SPEED_NOISE: f64 = 2.0; // km/h, speed error at hdop 1
+const ACCELERATION_NOISE: f64 = 4.0; // km/h per second the speed is expected to change, higher follows faster
+const STATIONARY_SPEED: f64 = 2.0; // km/h, slower filtered speeds are published as 0, as for the odometer
+const HEADING_HOLD_SPEED: f64 = 3.0; // km/h, heading is held below this as it is mostly noise
+const HEADING_FULL_SPEED: f64 = 20.0; // km/h, heading follows the receiver fully above this
+const MIN_HEADING_WEIGHT: f64 = 0.2; // weight of a new heading just above the hold speed
+const KNOTS_TO_KPH: f64 = 1.852;
+
+// kalman filter on speed and a speed weighted complementary filter on heading
+#[derive(Default)]
+pub struct Smoother {
+    speed: Option<f64>, // km/h
+    variance: f64,
+    heading: Option<f64>,
+    last: Option<Instant>,
+}
+
+impl Smoother {
+    // replace the speed and bearing of a fix with the filtered values
+    pub fn smooth(&mut self, rmc: &mut RmcData, hdop: Option<f64>) {
+        let now = Instant::now();
+        let dt = self.last.map_or(1.0, |last| now.duration_since(last).as_secs_f64());
+        self.last = Some(now);
+
+        if let Some(measured) = rmc.sog_knots.map(|knots| knots * KNOTS_TO_KPH) {
+            let noise = SPEED_NOISE * hdop.unwrap_or(1.0).max(1.0);
+
+            let speed = match self.speed {
+                Some(speed) => {
+                    // predict, then correct with the measurement weighted by the gain
+                    self.variance += (ACCELERATION_NOISE * dt).powi(2);
+                    let gain = self.variance / (self.variance + noise * noise);
+                    self.variance *= 1.0 - gain;
+                    (speed + gain * (measured - speed)).max(0.0)
+                }
+                None => {
+                    self.variance = noise * noise;
+                    measured
+                }
+            };
+            self.speed = Some(speed);
+
+            let published = if speed < STATIONARY_SPEED { 0.0 } else { speed };
+            rmc.sog_knots = Some(published / KNOTS_TO_KPH);
+        }
+
+        if let Some(measured) = rmc.bearing {
+            let speed = self.speed.unwrap_or(0.0);
+
+            let heading = match self.heading {
+                Some(heading) if speed < HEADING_HOLD_SPEED => heading,
+                Some(heading) => {
+                    let weight = ((speed - HEADING_HOLD_SPEED) / (HEADING_FULL_SPEED - HEADING_HOLD_SPEED)).clamp(MIN_HEADING_WEIGHT, 1.0);
+
+                    // blend as unit vectors so 359 and 1 average to 0, not 180
+                    let (heading, measured) = (heading.to_radians(), measured.to_radians());
+                    let x = (1.0 - weight) * heading.cos() + weight * measured.cos();
+                    let y = (1.0 - weight) * heading.sin() + weight * measured.sin();
+                    y.atan2(x).to_degrees().rem_euclid(360.0)
+                }
+                None => measured,
+            };
+            self.heading = Some(heading);
+            rmc.bearing = Some(heading);
+        }
+    }
+}
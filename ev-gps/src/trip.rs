@@ -0,0 +1,202 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+const ODOMETER_PATH: &str = "odometer.dat"; // persisted distances, name=value lines
+const SAVE_RATE: Duration = Duration::from_secs(60); // rate to write distances to disk
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+const MIN_MOVING_SPEED: f64 = 2.0; // km/h, slower fixes are position jitter while stationary
+const MIN_STEP_M: f64 = 3.0; // shorter steps are held until they add up
+const MAX_SPEED: f64 = 250.0; // km/h, steps implying more are glitches and are not counted
+const TRIP_START_SPEED: f64 = 5.0; // km/h to start a trip
+const TRIP_END_IDLE: Duration = Duration::from_secs(300); // stationary time that ends a trip without ignition data
+
+struct Fix {
+    latitude: f64,
+    longitude: f64,
+    time: Instant,
+}
+
+struct Trip {
+    start: Instant,
+    distance_m: f64,
+    moving: Duration,
+    max_speed: f64,
+    stopped_since: Option<Instant>,
+}
+
+pub enum TripEvent {
+    Started,
+    Ended(String), // influxdb line protocol summary
+}
+
+pub struct Odometer {
+    pub total_m: f64,
+    pub trip_a_m: f64,
+    pub trip_b_m: f64,
+    anchor: Option<Fix>, // last counted position
+    last_fix: Option<Instant>,
+    ignition: Option<bool>, // none until the motor controller reports
+    trip: Option<Trip>,
+    last_save: Instant,
+}
+
+// great circle distance in meters
+pub fn haversine(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+impl Odometer {
+    // load distances from disk, starting from zero if there are none
+    pub fn load() -> Odometer {
+        let mut odometer = Odometer {
+            total_m: 0.0,
+            trip_a_m: 0.0,
+            trip_b_m: 0.0,
+            anchor: None,
+            last_fix: None,
+            ignition: None,
+            trip: None,
+            last_save: Instant::now(),
+        };
+
+        let contents = match fs::read_to_string(ODOMETER_PATH) {
+            Ok(c) => c,
+            Err(_) => {
+                println!("No odometer in {ODOMETER_PATH}, starting from zero");
+                return odometer;
+            }
+        };
+
+        for line in contents.lines() {
+            let Some((name, value)) = line.split_once('=') else { continue };
+
+            match name {
+                "total_m" => odometer.total_m = value.parse().unwrap_or(0.0),
+                "trip_a_m" => odometer.trip_a_m = value.parse().unwrap_or(0.0),
+                "trip_b_m" => odometer.trip_b_m = value.parse().unwrap_or(0.0),
+                _ => {}
+            }
+        }
+
+        odometer
+    }
+
+    pub fn save(&mut self) {
+        let contents = format!("total_m={:.1}\ntrip_a_m={:.1}\ntrip_b_m={:.1}\n", self.total_m, self.trip_a_m, self.trip_b_m);
+
+        // temporary file and rename, as for pack_lifetime.dat in ev-mcu
+        let temp_path = format!("{ODOMETER_PATH}.tmp");
+        if let Err(e) = fs::write(&temp_path, contents).and_then(|_| fs::rename(&temp_path, ODOMETER_PATH)) {
+            println!("Error writing {ODOMETER_PATH}: {:?}", e);
+        }
+
+        self.last_save = Instant::now();
+    }
+
+    // add the distance to a valid fix and start or end a trip from the speed
+    pub fn update_fix(&mut self, latitude: f64, longitude: f64, speed: f64) -> Option<TripEvent> {
+        let now = Instant::now();
+        let dt = self.last_fix.map(|last| now.duration_since(last));
+        self.last_fix = Some(now);
+
+        let moving = speed >= MIN_MOVING_SPEED;
+        let mut step_m = 0.0;
+
+        match &self.anchor {
+            // hold the anchor while stationary so jitter doesn't add up
+            Some(anchor) if moving => {
+                let distance = haversine(anchor.latitude, anchor.longitude, latitude, longitude);
+                let implied_speed = distance / now.duration_since(anchor.time).as_secs_f64().max(0.001) * 3.6;
+
+                if implied_speed > MAX_SPEED {
+                    self.anchor = Some(Fix { latitude, longitude, time: now });
+                } else if distance >= MIN_STEP_M {
+                    step_m = distance;
+                    self.anchor = Some(Fix { latitude, longitude, time: now });
+                }
+            }
+            Some(_) => (),
+            None => self.anchor = Some(Fix { latitude, longitude, time: now }),
+        }
+
+        self.total_m += step_m;
+        self.trip_a_m += step_m;
+        self.trip_b_m += step_m;
+
+        if self.last_save.elapsed() >= SAVE_RATE {
+            self.save();
+        }
+
+        if let Some(trip) = self.trip.as_mut() {
+            trip.distance_m += step_m;
+            trip.max_speed = trip.max_speed.max(speed);
+
+            if moving {
+                trip.moving += dt.unwrap_or_default();
+                trip.stopped_since = None;
+            } else if trip.stopped_since.is_none() {
+                trip.stopped_since = Some(now);
+            }
+
+            // without ignition data a long stop ends the trip
+            if self.ignition.is_none() && trip.stopped_since.is_some_and(|t| t.elapsed() >= TRIP_END_IDLE) {
+                return self.end_trip();
+            }
+        } else if speed >= TRIP_START_SPEED && self.ignition != Some(false) {
+            self.trip = Some(Trip { start: now, distance_m: 0.0, moving: Duration::ZERO, max_speed: speed, stopped_since: None });
+            return Some(TripEvent::Started);
+        }
+
+        None
+    }
+
+    // key switch from the motor controller, turning it off ends the trip
+    pub fn set_ignition(&mut self, on: bool) -> Option<TripEvent> {
+        self.ignition = Some(on);
+
+        if !on {
+            return self.end_trip();
+        }
+        None
+    }
+
+    fn end_trip(&mut self) -> Option<TripEvent> {
+        let trip = self.trip.take()?;
+        self.save();
+
+        let duration = trip.start.elapsed().as_secs_f64();
+        let moving = trip.moving.as_secs_f64();
+        let average_speed = if moving > 0.0 { trip.distance_m / moving * 3.6 } else { 0.0 };
+
+        Some(TripEvent::Ended(format!(
+            "trip,device=gps distance_km={:.2},duration_s={:.0},moving_s={:.0},max_speed={:.1},average_speed={:.1},odometer_km={:.1}",
+            trip.distance_m / 1000.0,
+            duration,
+            moving,
+            trip.max_speed,
+            average_speed,
+            self.total_m / 1000.0
+        )))
+    }
+
+    // reset trip meter a or b, false for an unknown meter
+    pub fn reset(&mut self, meter: &str) -> bool {
+        match meter {
+            "a" => self.trip_a_m = 0.0,
+            "b" => self.trip_b_m = 0.0,
+            _ => return false,
+        }
+        self.save();
+        true
+    }
+
+    // distance of the trip in progress
+    pub fn trip_distance_m(&self) -> Option<f64> {
+        self.trip.as_ref().map(|t| t.distance_m)
+    }
+}
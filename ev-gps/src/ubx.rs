@@ -0,0 +1,265 @@
+// u-blox ubx binary protocol, configuration at startup and NAV-PVT decoding
+use std::time::{Duration, Instant};
+
+use nmea_parser::chrono::{DateTime, NaiveDate, Utc};
+use serialport::SerialPort;
+
+use crate::framer::{Framer, Packet};
+
+pub const SYNC: [u8; 2] = [0xB5, 0x62];
+pub const UBX_BAUD_RATE: u32 = 115200; // 9600 can't carry nmea at more than a couple of hz
+pub const MAX_RATE_HZ: u16 = 10;
+const ACK_TIMEOUT: Duration = Duration::from_millis(1000); // time to wait for the receiver to acknowledge a message
+const BAUD_SETTLE: Duration = Duration::from_millis(100); // time for the receiver to switch baud rate
+const CONFIGURE_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+const CLASS_NAV: u8 = 0x01;
+const CLASS_ACK: u8 = 0x05;
+const CLASS_CFG: u8 = 0x06;
+const CLASS_NMEA: u8 = 0xF0;
+const NAV_PVT: u8 = 0x07;
+const ACK_NAK: u8 = 0x00;
+const ACK_ACK: u8 = 0x01;
+const CFG_PRT: u8 = 0x00;
+const CFG_MSG: u8 = 0x01;
+const CFG_RATE: u8 = 0x08;
+const CFG_NAV5: u8 = 0x24;
+const NAV_PVT_MIN_LEN: usize = 84; // u-blox 7 sends 84 bytes, 8 and later 92, every field read is before offset 78
+const DYN_MODEL_AUTOMOTIVE: u8 = 4;
+
+// nmea message ids, the rate is in navigation epochs per sentence, 0 turns the sentence off
+// kept: rmc for position, gga for altitude and satellites, gsa for fix quality, gsv once a second for the sky plot
+// off: gll and vtg repeat rmc, zda and gst are replaced by NAV-PVT time and accuracy once the receiver accepts NAV-PVT
+const NMEA_GGA: u8 = 0x00;
+const NMEA_GLL: u8 = 0x01;
+const NMEA_GSA: u8 = 0x02;
+const NMEA_GSV: u8 = 0x03;
+const NMEA_RMC: u8 = 0x04;
+const NMEA_VTG: u8 = 0x05;
+const NMEA_GST: u8 = 0x07;
+const NMEA_ZDA: u8 = 0x08;
+
+pub struct Frame {
+    pub class: u8,
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+// UBX-NAV-PVT navigation solution, the time, accuracy and fix flags nmea doesn't carry
+pub struct Pvt {
+    pub time: Option<DateTime<Utc>>, // none until the date and time are valid and fully resolved
+    pub time_accuracy_ns: u32,
+    pub fix_type: u8, // 0 none, 1 dead reckoning, 2 2d, 3 3d, 4 gnss and dead reckoning, 5 time only
+    pub gnss_fix_ok: bool, // fix within the receiver's accuracy masks
+    pub satellites_used: u8,
+    pub horizontal_accuracy: f64, // m
+    pub vertical_accuracy: f64, // m
+    pub speed_accuracy: f64, // km/h
+    pub heading_accuracy: f64, // degrees
+    pub pdop: f64,
+}
+
+// 8 bit fletcher checksum over class, id, length and payload
+pub fn checksum(bytes: &[u8]) -> [u8; 2] {
+    bytes.iter().fold([0u8, 0u8], |[a, b], byte| {
+        let a = a.wrapping_add(*byte);
+        [a, b.wrapping_add(a)]
+    })
+}
+
+pub fn build_frame(class: u8, id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = SYNC.to_vec();
+    frame.extend_from_slice(&[class, id]);
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&checksum(&frame[2..]));
+    frame
+}
+
+fn u16_at(payload: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([payload[offset], payload[offset + 1]])
+}
+
+fn u32_at(payload: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([payload[offset], payload[offset + 1], payload[offset + 2], payload[offset + 3]])
+}
+
+fn i32_at(payload: &[u8], offset: usize) -> i32 {
+    u32_at(payload, offset) as i32
+}
+
+impl Frame {
+    pub fn parse_pvt(&self) -> Option<Pvt> {
+        if self.class != CLASS_NAV || self.id != NAV_PVT || self.payload.len() < NAV_PVT_MIN_LEN {
+            return None;
+        }
+        let p = &self.payload;
+
+        // valid date, valid time and fully resolved, nano is a signed correction to the second
+        let time = if p[11] & 0x07 == 0x07 {
+            NaiveDate::from_ymd_opt(u16_at(p, 4) as i32, p[6] as u32, p[7] as u32)
+                .and_then(|date| date.and_hms_opt(p[8] as u32, p[9] as u32, p[10] as u32))
+                .map(|time| time.and_utc() + nmea_parser::chrono::Duration::nanoseconds(i32_at(p, 16) as i64))
+        } else {
+            None
+        };
+
+        Some(Pvt {
+            time,
+            time_accuracy_ns: u32_at(p, 12),
+            fix_type: p[20],
+            gnss_fix_ok: p[21] & 0x01 != 0,
+            satellites_used: p[23],
+            horizontal_accuracy: u32_at(p, 40) as f64 / 1000.0,
+            vertical_accuracy: u32_at(p, 44) as f64 / 1000.0,
+            speed_accuracy: u32_at(p, 68) as f64 * 0.0036,
+            heading_accuracy: u32_at(p, 72) as f64 * 1e-5,
+            pdop: u16_at(p, 76) as f64 * 0.01,
+        })
+    }
+}
+
+impl Pvt {
+    // fix usable for position, 2d or 3d with or without dead reckoning
+    pub fn position_ok(&self) -> bool {
+        self.gnss_fix_ok && (2..=4).contains(&self.fix_type)
+    }
+
+    // influxdb fields
+    pub fn fields(&self) -> String {
+        format!(
+            "fix_type={},gnss_fix_ok={},satellites_used={},horizontal_accuracy={:.2},vertical_accuracy={:.2},speed_accuracy={:.2},heading_accuracy={:.1},time_accuracy_ns={},pvt_pdop={:.2}",
+            self.fix_type,
+            self.gnss_fix_ok as i32,
+            self.satellites_used,
+            self.horizontal_accuracy,
+            self.vertical_accuracy,
+            self.speed_accuracy,
+            self.heading_accuracy,
+            self.time_accuracy_ns,
+            self.pdop
+        )
+    }
+}
+
+// uart1 at a baud rate, 8N1, ubx and nmea in and out
+fn cfg_prt(baud_rate: u32) -> Vec<u8> {
+    let mut payload = vec![0u8; 20];
+    payload[0] = 1; // uart1
+    payload[4..8].copy_from_slice(&0x0000_08D0u32.to_le_bytes()); // 8 data bits, no parity, 1 stop bit
+    payload[8..12].copy_from_slice(&baud_rate.to_le_bytes());
+    payload[12..14].copy_from_slice(&0x0003u16.to_le_bytes()); // ubx and nmea in
+    payload[14..16].copy_from_slice(&0x0003u16.to_le_bytes()); // ubx and nmea out
+    build_frame(CLASS_CFG, CFG_PRT, &payload)
+}
+
+// measurement period, one navigation solution per measurement, aligned to gps time
+fn cfg_rate(rate_hz: u16) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(1000 / rate_hz).to_le_bytes());
+    payload.extend_from_slice(&1u16.to_le_bytes());
+    payload.extend_from_slice(&1u16.to_le_bytes());
+    build_frame(CLASS_CFG, CFG_RATE, &payload)
+}
+
+// only the dynamic model is applied, the rest of the navigation settings are left alone
+fn cfg_nav5(dyn_model: u8) -> Vec<u8> {
+    let mut payload = vec![0u8; 36];
+    payload[0..2].copy_from_slice(&0x0001u16.to_le_bytes());
+    payload[2] = dyn_model;
+    build_frame(CLASS_CFG, CFG_NAV5, &payload)
+}
+
+// output rate of a message on the current port in navigation epochs
+fn cfg_msg(class: u8, id: u8, rate: u8) -> Vec<u8> {
+    build_frame(CLASS_CFG, CFG_MSG, &[class, id, rate])
+}
+
+// wait for ACK-ACK or ACK-NAK of a cfg message
+fn wait_ack(port: &mut Box<dyn SerialPort>, id: u8) -> Result<(), String> {
+    let mut framer = Framer::default();
+    let mut buf = [0u8; 256];
+    let start = Instant::now();
+
+    while start.elapsed() < ACK_TIMEOUT {
+        let len = match port.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(format!("Read error: {e}")),
+        };
+
+        for packet in framer.push(&buf[..len]) {
+            let Packet::Ubx(frame) = packet else { continue };
+            if frame.class != CLASS_ACK || frame.payload.get(..2) != Some(&[CLASS_CFG, id]) {
+                continue;
+            }
+            return match frame.id {
+                ACK_ACK => Ok(()),
+                ACK_NAK => Err("rejected".to_string()),
+                _ => continue,
+            };
+        }
+    }
+
+    Err("no acknowledgement".to_string())
+}
+
+fn send(port: &mut Box<dyn SerialPort>, frame: &[u8]) -> Result<(), String> {
+    port.write_all(frame).and_then(|_| port.flush()).map_err(|e| format!("Write error: {e}"))
+}
+
+// switch the receiver to UBX_BAUD_RATE and rate_hz navigation, automotive model, the sentences ev-gps uses and NAV-PVT
+// settings are not saved to the receiver's flash, so they are sent again after every reconnect
+pub fn configure(port: &mut Box<dyn SerialPort>, rate_hz: u16) -> Result<(), String> {
+    let read_timeout = port.timeout();
+    let baud_rate = port.baud_rate().map_err(|e| format!("{e}"))?;
+    port.set_timeout(CONFIGURE_READ_TIMEOUT).map_err(|e| format!("{e}"))?;
+
+    // the receiver changes baud before it can acknowledge, if it was already at the new rate this is ignored
+    send(port, &cfg_prt(UBX_BAUD_RATE))?;
+    std::thread::sleep(BAUD_SETTLE);
+    port.set_baud_rate(UBX_BAUD_RATE).map_err(|e| format!("Failed to set baud rate: {e}"))?;
+    port.clear(serialport::ClearBuffer::Input).map_err(|e| format!("{e}"))?;
+
+    let gsv_rate = rate_hz as u8; // once a second
+    let messages = [
+        ("rate", cfg_rate(rate_hz)),
+        ("dynamic model", cfg_nav5(DYN_MODEL_AUTOMOTIVE)),
+        ("NAV-PVT", cfg_msg(CLASS_NAV, NAV_PVT, 1)),
+        ("RMC", cfg_msg(CLASS_NMEA, NMEA_RMC, 1)),
+        ("GGA", cfg_msg(CLASS_NMEA, NMEA_GGA, 1)),
+        ("GSA", cfg_msg(CLASS_NMEA, NMEA_GSA, 1)),
+        ("GSV", cfg_msg(CLASS_NMEA, NMEA_GSV, gsv_rate)),
+        ("GLL", cfg_msg(CLASS_NMEA, NMEA_GLL, 0)),
+        ("VTG", cfg_msg(CLASS_NMEA, NMEA_VTG, 0)),
+    ];
+    // a receiver without NAV-PVT, e.g. u-blox 6, keeps zda and gst
+    let replaced_by_pvt = [("GST", cfg_msg(CLASS_NMEA, NMEA_GST, 0)), ("ZDA", cfg_msg(CLASS_NMEA, NMEA_ZDA, 0))];
+
+    let mut failed = Vec::new();
+    for (name, frame) in messages.iter() {
+        send(port, frame)?;
+        if let Err(e) = wait_ack(port, frame[3]) {
+            println!("UBX {name} configuration {e}");
+            failed.push(*name);
+        }
+    }
+    if !failed.contains(&"NAV-PVT") {
+        for (name, frame) in replaced_by_pvt.iter() {
+            send(port, frame)?;
+            if let Err(e) = wait_ack(port, frame[3]) {
+                println!("UBX {name} configuration {e}");
+            }
+        }
+    }
+
+    port.set_timeout(read_timeout).map_err(|e| format!("{e}"))?;
+
+    // not a u-blox or not listening, go back to the baud rate it was found at
+    if failed.len() == messages.len() {
+        port.set_baud_rate(baud_rate).map_err(|e| format!("Failed to set baud rate: {e}"))?;
+        return Err(format!("No response from the receiver at {UBX_BAUD_RATE} baud, is it a u-blox?"));
+    }
+    println!("Configured u-blox receiver for {rate_hz} Hz at {UBX_BAUD_RATE} baud");
+    Ok(())
+}
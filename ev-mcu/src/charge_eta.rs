@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use crate::states::ChargeState;
+
+pub const DEFAULT_TARGET_SOC: u8 = 80; // target soc used when none is given on the command line
+const TAPER_START_SOC: f32 = 90.0; // soc where the charger is expected to leave bulk and start tapering
+const TAPER_END_FRACTION: f32 = 0.05; // taper ends when power falls to this fraction of bulk power
+const POWER_SMOOTHING: f32 = 0.05; // weight of each new 100ms sample in the charge power average
+const MIN_CHARGE_POWER: f32 = 50.0; // W, below this no estimate is made
+const PUBLISH_RATE: Duration = Duration::from_millis(1000); // rate to publish estimates
+
+pub struct ChargeEta {
+    target_soc: u8,
+    pack_kwh_current: Option<f32>,
+    pack_kwh_max: Option<f32>,
+    charge_state: Option<ChargeState>,
+    charge_power: Option<f32>, // W, smoothed
+    bulk_power: Option<f32>, // W, last smoothed power seen in bulk, start point of the taper
+    last_publish: Option<Instant>,
+}
+
+pub struct Estimate {
+    pub minutes_to_full: f32,
+    pub minutes_to_target: f32,
+    pub target_soc: u8,
+    pub charge_power: f32,
+}
+
+impl ChargeEta {
+    pub fn new(target_soc: u8) -> ChargeEta {
+        ChargeEta {
+            target_soc: target_soc.min(100),
+            pack_kwh_current: None,
+            pack_kwh_max: None,
+            charge_state: None,
+            charge_power: None,
+            bulk_power: None,
+            last_publish: None,
+        }
+    }
+
+    // true when charging has just stopped, so the last estimate can be cleared
+    pub fn update_charge_state(&mut self, charge_state: ChargeState) -> bool {
+        let was_charging = self.charge_state.is_some_and(|s| s.is_charging());
+        if !charge_state.is_charging() {
+            self.charge_power = None;
+            self.bulk_power = None;
+        }
+        self.charge_state = Some(charge_state);
+
+        was_charging && !charge_state.is_charging()
+    }
+
+    pub fn update_soc(&mut self, pack_kwh_current: f32, pack_kwh_max: f32) {
+        self.pack_kwh_current = Some(pack_kwh_current);
+        self.pack_kwh_max = Some(pack_kwh_max);
+    }
+
+    // feed a pack summary sample, returns an estimate at the publish rate while charging
+    pub fn update_pack(&mut self, pack_voltage: f32, pack_current: f32) -> Option<Estimate> {
+        if !self.charge_state.is_some_and(|s| s.is_charging()) {
+            return None;
+        }
+
+        let power = pack_voltage * pack_current.max(0.0); // charge current is positive
+        let smoothed = match self.charge_power {
+            Some(p) => p + (power - p) * POWER_SMOOTHING,
+            None => power,
+        };
+        self.charge_power = Some(smoothed);
+
+        if self.charge_state == Some(ChargeState::Bulk) {
+            self.bulk_power = Some(smoothed);
+        }
+
+        let now = Instant::now();
+        if self.last_publish.is_some_and(|last| now.duration_since(last) < PUBLISH_RATE) {
+            return None;
+        }
+        self.last_publish = Some(now);
+
+        self.estimate()
+    }
+
+    fn estimate(&self) -> Option<Estimate> {
+        let power = self.charge_power?;
+        let kwh_current = self.pack_kwh_current?;
+        let kwh_max = self.pack_kwh_max?;
+
+        if power < MIN_CHARGE_POWER || kwh_max <= 0.0 {
+            return None;
+        }
+
+        let target_kwh = kwh_max * self.target_soc as f32 / 100.0;
+
+        Some(Estimate {
+            minutes_to_full: self.hours_to(kwh_current, kwh_max, kwh_max, power) * 60.0,
+            minutes_to_target: self.hours_to(kwh_current, target_kwh, kwh_max, power) * 60.0,
+            target_soc: self.target_soc,
+            charge_power: power,
+        })
+    }
+
+    // hours to charge from the current energy to the goal, constant power in bulk then an exponential taper
+    fn hours_to(&self, kwh_current: f32, kwh_goal: f32, kwh_max: f32, power: f32) -> f32 {
+        if kwh_goal <= kwh_current {
+            return 0.0;
+        }
+
+        let taper_start_kwh = kwh_max * TAPER_START_SOC / 100.0;
+        let bulk_power = self.bulk_power.unwrap_or(power).max(power);
+        let end_power = bulk_power * TAPER_END_FRACTION;
+
+        let mut hours = 0.0;
+        let mut kwh = kwh_current;
+        let mut taper_power = power;
+
+        // constant power until the taper starts
+        if self.charge_state == Some(ChargeState::Bulk) && kwh < taper_start_kwh {
+            let bulk_kwh = kwh_goal.min(taper_start_kwh) - kwh;
+            hours += bulk_kwh / (power / 1000.0);
+            kwh += bulk_kwh;
+            taper_power = bulk_power;
+        }
+
+        // power decays from taper_power towards end_power, the average power over the rest of the taper
+        // is (P - Pend) / ln(P / Pend), scaled by how much of the remaining taper energy is needed
+        if kwh_goal > kwh && taper_power > end_power {
+            let taper_kwh = kwh_max - kwh;
+            let taper_hours = taper_kwh * (taper_power / end_power).ln() / ((taper_power - end_power) / 1000.0);
+            hours += taper_hours_to(taper_hours, taper_power, end_power, (kwh_goal - kwh) / taper_kwh);
+        }
+
+        hours
+    }
+}
+
+// time to deliver a fraction of the taper energy, with P(t) = P0 * exp(-t / tau)
+fn taper_hours_to(taper_hours: f32, start_power: f32, end_power: f32, fraction: f32) -> f32 {
+    if fraction >= 1.0 {
+        return taper_hours;
+    }
+
+    let tau = taper_hours / (start_power / end_power).ln();
+    let delivered = fraction * (1.0 - end_power / start_power);
+
+    -tau * (1.0 - delivered).ln()
+}
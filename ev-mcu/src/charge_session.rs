@@ -0,0 +1,203 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::states::{ChargeState, PlugState};
+
+const CHARGE_HISTORY_PATH: &str = "charge_sessions.log"; // local history of session summaries, influxdb line protocol
+const MIN_SESSION_DURATION: Duration = Duration::from_secs(60); // ignore plug blips shorter than this
+
+// charge phases tracked individually, anything else is counted as idle
+const CHARGE_PHASES: [(ChargeState, &str); 4] = [
+    (ChargeState::Bulk, "bulk_s"),
+    (ChargeState::Finish, "finish_s"),
+    (ChargeState::Float, "float_s"),
+    (ChargeState::TopBalance, "top_balance_s"),
+];
+
+struct ChargeSession {
+    start_time: SystemTime,
+    start: Instant,
+    start_soc: Option<u8>,
+    end_soc: Option<u8>,
+    last_charge_kwh: Option<f32>, // latest mcu charge counter reading
+    energy_kwh: Option<f32>, // none when the counter wasn't known at plug in
+    phase_durations: [Duration; 4],
+    phase: Option<usize>, // index into CHARGE_PHASES of the current phase
+    phase_since: Instant,
+    peak_pack_temp: Option<i8>,
+    max_cell_voltage: Option<f32>,
+}
+
+#[derive(Default)]
+pub struct ChargeSessionRecorder {
+    session: Option<ChargeSession>,
+    soc: Option<u8>,
+    charge_kwh: Option<f32>,
+    charge_state: Option<ChargeState>,
+}
+
+impl ChargeSession {
+    fn new(soc: Option<u8>, charge_kwh: Option<f32>, charge_state: Option<ChargeState>) -> ChargeSession {
+        let now = Instant::now();
+
+        ChargeSession {
+            start_time: SystemTime::now(),
+            start: now,
+            start_soc: soc,
+            end_soc: soc,
+            last_charge_kwh: charge_kwh,
+            energy_kwh: charge_kwh.map(|_| 0.0),
+            phase_durations: [Duration::ZERO; 4],
+            phase: phase_index(charge_state),
+            phase_since: now,
+            peak_pack_temp: None,
+            max_cell_voltage: None,
+        }
+    }
+
+    // close out the time spent in the current phase and move to the next one
+    fn set_phase(&mut self, charge_state: Option<ChargeState>) {
+        let now = Instant::now();
+
+        if let Some(phase) = self.phase {
+            self.phase_durations[phase] += now.duration_since(self.phase_since);
+        }
+
+        self.phase = phase_index(charge_state);
+        self.phase_since = now;
+    }
+
+    // add the change in the mcu counter, which may reset at plug in, after a drop it counts up from zero again
+    fn update_charge_kwh(&mut self, charge_kwh: f32) {
+        if let (Some(energy), Some(last)) = (self.energy_kwh.as_mut(), self.last_charge_kwh) {
+            *energy += if charge_kwh >= last { charge_kwh - last } else { charge_kwh };
+        }
+        self.last_charge_kwh = Some(charge_kwh);
+    }
+
+    fn summary(&self) -> String {
+        let mut payload = format!(
+            "charge_session,system=mcu start_time={}i,duration_s={}i",
+            self.start_time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            self.start.elapsed().as_secs()
+        );
+
+        if let Some(energy_kwh) = self.energy_kwh {
+            payload.push_str(format!(",energy_kwh={energy_kwh:.2}").as_str());
+        }
+
+        for (i, (_, field)) in CHARGE_PHASES.iter().enumerate() {
+            payload.push_str(format!(",{field}={}i", self.phase_durations[i].as_secs()).as_str());
+        }
+
+        if let Some(start_soc) = self.start_soc {
+            payload.push_str(format!(",start_soc={start_soc}i").as_str());
+        }
+
+        if let Some(end_soc) = self.end_soc {
+            payload.push_str(format!(",end_soc={end_soc}i").as_str());
+        }
+
+        if let Some(peak_pack_temp) = self.peak_pack_temp {
+            payload.push_str(format!(",peak_pack_temp={peak_pack_temp}i").as_str());
+        }
+
+        if let Some(max_cell_voltage) = self.max_cell_voltage {
+            payload.push_str(format!(",max_cell_voltage={max_cell_voltage:.4}").as_str());
+        }
+
+        payload
+    }
+}
+
+impl ChargeSessionRecorder {
+    // start a session on plug in, returns the summary line when a session ends on unplug
+    pub fn update_plug_state(&mut self, charge_plug_state: PlugState) -> Option<String> {
+        match charge_plug_state {
+            s if s.is_plugged_in() => {
+                if self.session.is_none() {
+                    println!("Charge session started");
+                    self.session = Some(ChargeSession::new(self.soc, self.charge_kwh, self.charge_state));
+                }
+                None
+            }
+            PlugState::Disconnected => {
+                let mut session = self.session.take()?;
+                session.set_phase(None);
+
+                if session.start.elapsed() < MIN_SESSION_DURATION {
+                    return None;
+                }
+
+                let summary = session.summary();
+                println!("Charge session ended: {summary}");
+                append_history(&summary);
+
+                Some(summary)
+            }
+            _ => None, // unknown plug state, keep the session as is
+        }
+    }
+
+    pub fn update_charge_state(&mut self, charge_state: ChargeState) {
+        if self.charge_state == Some(charge_state) {
+            return;
+        }
+
+        if let Some(session) = self.session.as_mut() {
+            session.set_phase(Some(charge_state));
+        }
+        self.charge_state = Some(charge_state);
+    }
+
+    pub fn update_charge_kwh(&mut self, charge_kwh: f32) {
+        self.charge_kwh = Some(charge_kwh);
+
+        if let Some(session) = self.session.as_mut() {
+            session.update_charge_kwh(charge_kwh);
+        }
+    }
+
+    pub fn update_soc(&mut self, soc: u8) {
+        self.soc = Some(soc);
+
+        if let Some(session) = self.session.as_mut() {
+            if session.start_soc.is_none() {
+                session.start_soc = Some(soc);
+            }
+            session.end_soc = Some(soc);
+        }
+    }
+
+    pub fn update_pack_temp(&mut self, thermistor_temp_high: i8) {
+        if let Some(session) = self.session.as_mut() {
+            session.peak_pack_temp = Some(session.peak_pack_temp.map_or(thermistor_temp_high, |t| t.max(thermistor_temp_high)));
+        }
+    }
+
+    pub fn update_cell_voltage_high(&mut self, cell_voltage_high: f32) {
+        if let Some(session) = self.session.as_mut() {
+            session.max_cell_voltage = Some(session.max_cell_voltage.map_or(cell_voltage_high, |v| v.max(cell_voltage_high)));
+        }
+    }
+}
+
+fn phase_index(charge_state: Option<ChargeState>) -> Option<usize> {
+    CHARGE_PHASES.iter().position(|(state, _)| Some(*state) == charge_state)
+}
+
+// append the summary with a timestamp so the history file can be imported into influxdb
+fn append_history(summary: &str) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(CHARGE_HISTORY_PATH)
+        .and_then(|mut file| writeln!(file, "{summary} {timestamp}"));
+
+    if let Err(e) = result {
+        println!("Error writing {CHARGE_HISTORY_PATH}: {:?}", e);
+    }
+}
@@ -8,6 +8,17 @@ use std::env;
 
 extern crate paho_mqtt as mqtt;
 
+mod charge_eta;
+mod charge_session;
+mod pack_lifetime;
+mod params;
+mod states;
+
+use charge_eta::{ChargeEta, DEFAULT_TARGET_SOC};
+use charge_session::ChargeSessionRecorder;
+use pack_lifetime::PackLifetime;
+use states::{ChargeState, PlugState, StateCode};
+
 const MSG_LEN: usize = 13; // message size
 const MQTT_IP: &str = "tcp://127.0.0.1:1883";
 const MQTT_CLIENT_ID: &str = "can-mcu";
@@ -37,18 +48,44 @@ const PGN_FAST: [[u8; 4]; 2] =
     ];
 
 fn main() {
-    let mqtt_client = open_mqtt_connection();
+    let mut args: Vec<String> = env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("params") {
+        if let Err(e) = params::run_cli(&args[2..]) {
+            println!("{e}");
+            process::exit(1);
+        }
+        return;
+    }
 
-    let iface = env::args().nth(1).unwrap_or_else(|| CAN_INTERFACE.into());
+    let mut target_soc = DEFAULT_TARGET_SOC;
+    if let Some(i) = args.iter().position(|a| a == "--target-soc") {
+        target_soc = args.get(i + 1).and_then(|v| v.parse().ok()).unwrap_or_else(|| {
+            println!("--target-soc requires a value from 0 to 100");
+            process::exit(1);
+        });
+        args.drain(i..i + 2);
+    }
+
+    // the parameter api sends unconfirmed frames on the bus, so it only runs when asked for
+    let params_api = args.iter().position(|a| a == params::UNCONFIRMED_FLAG).map(|i| args.remove(i)).is_some();
+
+    let mqtt_client = open_mqtt_connection(MQTT_CLIENT_ID);
+
+    let iface = args.get(1).cloned().unwrap_or_else(|| CAN_INTERFACE.into());
 
     let mut sock: CanSocket = CanSocket::open(&iface).expect("Failed to open socket");
 
+    if params_api {
+        params::spawn_mqtt_api(iface.clone());
+    }
+
     // create new thread for sending requests
-    thread::spawn(|| {
+    let request_iface = iface.clone();
+    thread::spawn(move || {
         let mut last: Instant = Instant::now() - REQUEST_RATE_SLOW;
         let mut now: Instant;
 
-        let iface = env::args().nth(1).unwrap_or_else(|| CAN_INTERFACE.into());
+        let iface = request_iface;
         let mut sock: CanSocket = CanSocket::open(&iface).expect("Failed to open socket");
 
         loop {
@@ -76,10 +113,18 @@ fn main() {
         }
     });
 
+    let mut state = McuState {
+        charge_state: None,
+        charge_plug_state: None,
+        charge_session: ChargeSessionRecorder::default(),
+        charge_eta: ChargeEta::new(target_soc),
+        pack_lifetime: PackLifetime::load(),
+    };
+
     loop {
         match sock.receive() {
             Ok(f) => {
-                decode_message(&mqtt_client, f)
+                decode_message(&mqtt_client, f, &mut state)
             }
             Err(e) => {
                 //eprintln!("Receive Error: {:?}", e);
@@ -90,7 +135,7 @@ fn main() {
     //close_mqtt_connection(mqtt_client);
 }
 
-fn decode_message(mqtt_client: &Client, frame: CanFrame) {
+fn decode_message(mqtt_client: &Client, frame: CanFrame, state: &mut McuState) {
     let message_id = frame.raw_id(); // combine bytes of message id into a word
     let message = frame.data();
 
@@ -128,25 +173,8 @@ fn decode_message(mqtt_client: &Client, frame: CanFrame) {
         0x14ff20d0 => {
             // PGN_MCUSUM
             let charge_kwh: f32 = (bytes_to_word_unsigned(message[2], message[3]) as f32) / 100.0;
-            let charge_state = match message[4] {
-                0 => "Standby",
-                1 => "Startup",
-                2 => "Warmdown",
-                10 => "Bulk",
-                11 => "Finish",
-                12 => "Float",
-                13 => "Top Balance",
-                _ => "N/A"
-            };
-            let charge_plug_state = match message[5]{
-                0 => "Unknown",
-                1 => "Disconnected",
-                2 => "Connected",
-                3 => "Locked",
-                4 => "Waiting For Disc",
-                5 => "Active",
-                _ => "N/A"
-            };
+            let charge_state = ChargeState::from_code(message[4]);
+            let charge_plug_state = PlugState::from_code(message[5]);
             let bms_alerts: u16 = bytes_to_word_unsigned(message[6], message[7]);
             let w_alerts: [BitField; 9] = [
                 BitField { name: "BMS_FAULT_ILLEGAL_CONF".to_string(), mask: 0x0040 },
@@ -174,23 +202,58 @@ fn decode_message(mqtt_client: &Client, frame: CanFrame) {
             //println!("Charge Plug State: {charge_plug_state}");
             //println!("BMS Alerts: {bms_alerts:x}");
 
-            let payload = format!("power,system=mcu charge_kwh={charge_kwh},charge_state=\"{charge_state}\",charge_plug_state=\"{charge_plug_state}\"");
+            let payload = format!(
+                "power,system=mcu charge_kwh={charge_kwh},charge_state_code={},charge_state=\"{}\",charge_plug_state_code={},charge_plug_state=\"{}\"",
+                charge_state.code(), charge_state, charge_plug_state.code(), charge_plug_state
+            );
             write_mqtt_message(mqtt_client, "mcu", payload.as_str());
             write_mqtt_message(mqtt_client, "live/mcu/charge_kwh", format!("{charge_kwh}").as_str()); //live data for dashboard
-            write_mqtt_message(mqtt_client, "live/mcu/charge_state", format!("{charge_state}").as_str()); //live data for dashboard
-            write_mqtt_message(mqtt_client, "live/mcu/charge_plug_state", format!("{charge_plug_state}").as_str()); //live data for dashboard
+            write_mqtt_message(mqtt_client, "live/mcu/charge_state", charge_state.to_string().as_str()); //live data for dashboard
+            write_mqtt_message(mqtt_client, "live/mcu/charge_state_code", format!("{}", charge_state.code()).as_str()); //live data for dashboard
+            write_mqtt_message(mqtt_client, "live/mcu/charge_plug_state", charge_plug_state.to_string().as_str()); //live data for dashboard
+            write_mqtt_message(mqtt_client, "live/mcu/charge_plug_state_code", format!("{}", charge_plug_state.code()).as_str()); //live data for dashboard
+
+            if let Some(previous) = state.charge_state.replace(charge_state).filter(|s| *s != charge_state) {
+                log_transition(mqtt_client, "charge_state", &previous, &charge_state);
+            }
+            if let Some(previous) = state.charge_plug_state.replace(charge_plug_state).filter(|s| *s != charge_plug_state) {
+                log_transition(mqtt_client, "charge_plug_state", &previous, &charge_plug_state);
+            }
+
+            state.charge_session.update_charge_kwh(charge_kwh);
+            state.charge_session.update_charge_state(charge_state);
+            if state.charge_eta.update_charge_state(charge_state) {
+                write_mqtt_message(mqtt_client, "live/mcu/charge_eta", "none"); //live data for dashboard
+                write_mqtt_message(mqtt_client, "live/mcu/charge_eta_target", "none"); //live data for dashboard
+                state.pack_lifetime.save();
+            }
+            if let Some(summary) = state.charge_session.update_plug_state(charge_plug_state) {
+                write_mqtt_message(mqtt_client, "mcu", summary.as_str());
+                write_mqtt_message(mqtt_client, "live/mcu/charge_session", summary.as_str()); //live data for dashboard
+            }
         
         }
         0x14ff21d0 => {
             // PGN_PACKSUM
             let pack_voltage: f32 = (bytes_to_word_unsigned(message[2], message[3]) as f32) / 10.0; // extract and convert pack voltage
-            let pack_current: f32 = (bytes_to_word_signed(message[4], message[5]) as f32) / 10.0; // extract and convert pack current
+            let pack_current: f32 = (bytes_to_word_signed(message[4], message[5]) as f32) / 10.0; // extract and convert pack current, negative while discharging as the dashboard power gauge reads it
             //println!("Pack Voltage: {pack_voltage} V");
             //println!("Pack Current: {pack_current} A");
 
             let payload = format!("power,system=pack pack_voltage={pack_voltage},pack_current={pack_current}");
             write_mqtt_message(mqtt_client, "mcu", payload.as_str());
             write_mqtt_message(mqtt_client, "live/mcu/pack_current", format!("{pack_current}").as_str()); //live data for dashboard
+
+            if let Some(payload) = state.pack_lifetime.update_pack(pack_voltage, pack_current) {
+                write_mqtt_message(mqtt_client, "mcu", payload.as_str());
+            }
+
+            if let Some(eta) = state.charge_eta.update_pack(pack_voltage, pack_current) {
+                let payload = format!("power,system=charger charge_power={:.0},eta_full_min={:.0},eta_target_min={:.0},target_soc={}", eta.charge_power, eta.minutes_to_full, eta.minutes_to_target, eta.target_soc);
+                write_mqtt_message(mqtt_client, "mcu", payload.as_str());
+                write_mqtt_message(mqtt_client, "live/mcu/charge_eta", format!("{:.0}", eta.minutes_to_full).as_str()); //live data for dashboard
+                write_mqtt_message(mqtt_client, "live/mcu/charge_eta_target", format!("{:.0}", eta.minutes_to_target).as_str()); //live data for dashboard
+            }
         }
         0x14ff22d0 => {
             // PGN_CVSUM
@@ -204,6 +267,8 @@ fn decode_message(mqtt_client: &Client, frame: CanFrame) {
             let payload = format!("power,system=cells cell_voltage_low={cell_voltage_low},cell_voltage_mean={cell_voltage_mean},cell_voltage_high={cell_voltage_high}");
             write_mqtt_message(mqtt_client, "mcu", payload.as_str().clone());
             write_mqtt_message(mqtt_client, "live/mcu/cell_voltage_mean", format!("{cell_voltage_mean}").as_str()); //live data for dashboard        }
+
+            state.charge_session.update_cell_voltage_high(cell_voltage_high);
         }
         0x14ff23d0 => {
             //PGN_THSUM
@@ -222,6 +287,8 @@ fn decode_message(mqtt_client: &Client, frame: CanFrame) {
             write_mqtt_message(mqtt_client, "mcu", payload.as_str().clone());
             write_mqtt_message(mqtt_client, "live/mcu/pack_temp_low", format!("{thermistor_temp_low}").as_str()); //live data for dashboard
             write_mqtt_message(mqtt_client, "live/mcu/pack_temp_high", format!("{thermistor_temp_high}").as_str()); //live data for dashboard
+
+            state.charge_session.update_pack_temp(thermistor_temp_high);
         }
         0x14ff24d0 => {
             // PGN_SOCSUM
@@ -236,6 +303,10 @@ fn decode_message(mqtt_client: &Client, frame: CanFrame) {
             write_mqtt_message(mqtt_client, "live/mcu/soc", format!("{soc}").as_str()); //live data for dashboard
             write_mqtt_message(mqtt_client, "live/mcu/pack_kwh_current", format!("{pack_kwh_current}").as_str()); //live data for dashboard
             write_mqtt_message(mqtt_client, "live/mcu/pack_kwh_max", format!("{pack_kwh_max}").as_str()); //live data for dashboard
+
+            state.charge_session.update_soc(soc);
+            state.charge_eta.update_soc(pack_kwh_current, pack_kwh_max);
+            state.pack_lifetime.update_soc(soc, pack_kwh_max);
         }
         0x14ffc0d0 => {
             if message[0] == 0x00 {
@@ -257,6 +328,14 @@ fn decode_message(mqtt_client: &Client, frame: CanFrame) {
     }
 }
 
+// log a state change and publish it as an event
+fn log_transition<S: StateCode>(mqtt_client: &Client, name: &str, from: &S, to: &S) {
+    println!("{name}: {from} -> {to}");
+
+    let payload = format!("mcu_event,system=mcu,event={name} from_code={},to_code={},from=\"{from}\",to=\"{to}\"", from.raw_code(), to.raw_code());
+    write_mqtt_message(mqtt_client, "mcu", payload.as_str());
+}
+
 fn write_mqtt_message(mqtt_client: &Client, topic: &str, payload: &str) {
     if !mqtt_client.is_connected() {
         println!("Lost connection to mqtt broker");
@@ -275,10 +354,10 @@ fn write_mqtt_message(mqtt_client: &Client, topic: &str, payload: &str) {
     }
 }
 
-fn open_mqtt_connection() -> Client {
+fn open_mqtt_connection(client_id: &str) -> Client {
     let create_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(MQTT_IP)
-        .client_id(MQTT_CLIENT_ID.to_string())
+        .client_id(client_id.to_string())
         .finalize();
 
     // Create a client.
@@ -327,6 +406,15 @@ fn bytes_to_word_unsigned(a: u8, b: u8) -> u16 {
     return c; // return word
 }
 
+// decoded state carried between messages
+struct McuState {
+    charge_state: Option<ChargeState>,
+    charge_plug_state: Option<PlugState>,
+    charge_session: ChargeSessionRecorder,
+    charge_eta: ChargeEta,
+    pack_lifetime: PackLifetime,
+}
+
 struct BitField {
     name: String,
     mask: u16,
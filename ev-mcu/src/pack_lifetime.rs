@@ -0,0 +1,206 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+const PACK_LIFETIME_PATH: &str = "pack_lifetime.dat"; // persisted totals, name=value lines
+const SAVE_RATE: Duration = Duration::from_secs(60); // rate to write totals to disk while current flows
+const REST_CURRENT: f32 = 2.0; // A, below this the pack is at rest, e.g. after key off, and totals are saved
+const PUBLISH_RATE: Duration = Duration::from_secs(10); // rate to publish the pack_lifetime measurement
+const MAX_SAMPLE_GAP: Duration = Duration::from_millis(1000); // longer gaps between samples are not integrated
+const DOD_HYSTERESIS: u8 = 5; // soc reversal (%) needed to close a discharge or charge half cycle
+const DOD_BINS: usize = 10; // depth of discharge histogram in 10% bins
+
+// negative pack current is discharge, positive is charge, the convention of the mcu pack summary
+pub struct PackLifetime {
+    ah_in: f64,
+    ah_out: f64,
+    kwh_in: f64,
+    kwh_out: f64,
+    dod_histogram: [u32; DOD_BINS],
+    pack_kwh_max: f64,
+    discharging: bool,
+    soc_peak: Option<u8>, // highest soc since the last charge
+    soc_trough: Option<u8>, // lowest soc since the discharge started
+    last_sample: Option<Instant>,
+    flowing: bool, // current above REST_CURRENT since the last save
+    last_save: Instant,
+    last_publish: Instant,
+}
+
+impl PackLifetime {
+    // load totals from disk, starting from zero if there are none
+    pub fn load() -> PackLifetime {
+        let mut lifetime = PackLifetime {
+            ah_in: 0.0,
+            ah_out: 0.0,
+            kwh_in: 0.0,
+            kwh_out: 0.0,
+            dod_histogram: [0; DOD_BINS],
+            pack_kwh_max: 0.0,
+            discharging: false,
+            soc_peak: None,
+            soc_trough: None,
+            last_sample: None,
+            flowing: false,
+            last_save: Instant::now(),
+            last_publish: Instant::now(),
+        };
+
+        let contents = match fs::read_to_string(PACK_LIFETIME_PATH) {
+            Ok(c) => c,
+            Err(_) => {
+                println!("No pack lifetime totals in {PACK_LIFETIME_PATH}, starting from zero");
+                return lifetime;
+            }
+        };
+
+        for line in contents.lines() {
+            let Some((name, value)) = line.split_once('=') else { continue };
+
+            match name {
+                "ah_in" => lifetime.ah_in = value.parse().unwrap_or(0.0),
+                "ah_out" => lifetime.ah_out = value.parse().unwrap_or(0.0),
+                "kwh_in" => lifetime.kwh_in = value.parse().unwrap_or(0.0),
+                "kwh_out" => lifetime.kwh_out = value.parse().unwrap_or(0.0),
+                "pack_kwh_max" => lifetime.pack_kwh_max = value.parse().unwrap_or(0.0),
+                "discharging" => lifetime.discharging = value == "1",
+                "soc_peak" => lifetime.soc_peak = value.parse().ok(),
+                "soc_trough" => lifetime.soc_trough = value.parse().ok(),
+                "dod" => {
+                    for (i, count) in value.split(',').take(DOD_BINS).enumerate() {
+                        lifetime.dod_histogram[i] = count.parse().unwrap_or(0);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        lifetime
+    }
+
+    pub fn save(&mut self) {
+        let dod: Vec<String> = self.dod_histogram.iter().map(|c| c.to_string()).collect();
+
+        let mut contents = format!(
+            "ah_in={}\nah_out={}\nkwh_in={}\nkwh_out={}\npack_kwh_max={}\ndischarging={}\ndod={}\n",
+            self.ah_in, self.ah_out, self.kwh_in, self.kwh_out, self.pack_kwh_max, self.discharging as u8, dod.join(",")
+        );
+        if let Some(soc_peak) = self.soc_peak {
+            contents.push_str(format!("soc_peak={soc_peak}\n").as_str());
+        }
+        if let Some(soc_trough) = self.soc_trough {
+            contents.push_str(format!("soc_trough={soc_trough}\n").as_str());
+        }
+
+        // write to a temporary file first so a power cut can't leave a truncated file
+        let temp_path = format!("{PACK_LIFETIME_PATH}.tmp");
+        if let Err(e) = fs::write(&temp_path, contents).and_then(|_| fs::rename(&temp_path, PACK_LIFETIME_PATH)) {
+            println!("Error writing {PACK_LIFETIME_PATH}: {:?}", e);
+        }
+
+        self.flowing = false;
+        self.last_save = Instant::now();
+    }
+
+    // integrate a pack summary sample, returns the measurement at the publish rate
+    pub fn update_pack(&mut self, pack_voltage: f32, pack_current: f32) -> Option<String> {
+        let now = Instant::now();
+
+        if let Some(last) = self.last_sample {
+            let dt = now.duration_since(last);
+
+            if dt <= MAX_SAMPLE_GAP {
+                let hours = dt.as_secs_f64() / 3600.0;
+                let ah = pack_current as f64 * hours;
+                let kwh = (pack_voltage * pack_current) as f64 * hours / 1000.0;
+
+                if pack_current < 0.0 {
+                    self.ah_out -= ah;
+                    self.kwh_out -= kwh;
+                } else {
+                    self.ah_in += ah;
+                    self.kwh_in += kwh;
+                }
+            }
+        }
+        self.last_sample = Some(now);
+
+        // save when the pack comes to rest so a power cut after key off loses nothing
+        let at_rest = pack_current.abs() < REST_CURRENT;
+        if (at_rest && self.flowing) || now.duration_since(self.last_save) >= SAVE_RATE {
+            self.save();
+        }
+        self.flowing |= !at_rest;
+
+        if now.duration_since(self.last_publish) < PUBLISH_RATE {
+            return None;
+        }
+        self.last_publish = now;
+
+        Some(self.measurement())
+    }
+
+    pub fn update_soc(&mut self, soc: u8, pack_kwh_max: f32) {
+        if pack_kwh_max > 0.0 {
+            self.pack_kwh_max = pack_kwh_max as f64;
+        }
+
+        let soc = soc.min(100); // a garbage can byte shouldn't become a cycle
+        let peak = *self.soc_peak.get_or_insert(soc);
+        let trough = *self.soc_trough.get_or_insert(soc);
+
+        if self.discharging {
+            if soc < trough {
+                self.soc_trough = Some(soc);
+            } else if soc >= trough.saturating_add(DOD_HYSTERESIS) {
+                // charging again, close the discharge half cycle
+                self.record_depth(peak.saturating_sub(trough));
+                self.discharging = false;
+                self.soc_peak = Some(soc);
+            }
+        } else if soc > peak {
+            self.soc_peak = Some(soc);
+        } else if soc.saturating_add(DOD_HYSTERESIS) <= peak {
+            self.discharging = true;
+            self.soc_trough = Some(soc);
+        }
+    }
+
+    fn record_depth(&mut self, depth: u8) {
+        if depth == 0 {
+            return;
+        }
+
+        let bin = ((depth as usize) * DOD_BINS / 100).min(DOD_BINS - 1);
+        self.dod_histogram[bin] += 1;
+        println!("Discharge cycle recorded: {depth}% depth of discharge");
+
+        self.save();
+    }
+
+    // full cycles equivalent to the energy taken out of the pack
+    fn equivalent_cycles(&self) -> f64 {
+        if self.pack_kwh_max > 0.0 {
+            self.kwh_out / self.pack_kwh_max
+        } else {
+            0.0
+        }
+    }
+
+    fn measurement(&self) -> String {
+        let mut payload = format!(
+            "pack_lifetime,system=pack ah_in={:.3},ah_out={:.3},kwh_in={:.3},kwh_out={:.3},throughput_kwh={:.3},equivalent_cycles={:.3}",
+            self.ah_in,
+            self.ah_out,
+            self.kwh_in,
+            self.kwh_out,
+            self.kwh_in + self.kwh_out,
+            self.equivalent_cycles()
+        );
+
+        for (i, count) in self.dod_histogram.iter().enumerate() {
+            payload.push_str(format!(",dod_{:02}_{:02}={count}", i * 10, i * 10 + 10).as_str());
+        }
+
+        payload
+    }
+}
@@ -0,0 +1,331 @@
+use mqtt::Client;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use embedded_can::Frame as EmbeddedFrame;
+use socketcan::{CanFrame, CanSocket, ExtendedId, Frame, Socket};
+
+use crate::{bytes_to_word_signed, bytes_to_word_unsigned, open_mqtt_connection, write_mqtt_message, CAN_INTERFACE, EID_REQUEST_READ};
+
+const EID_REQUEST_WRITE: u32 = 0x14efd0d8; // message id for parameter write requests
+const EID_PARAM_RESPONSE: u32 = 0x14ff30d0; // PGN_PARAM response from the MCU
+const PGN_PARAM: [u8; 2] = [0x30, 0xFF]; // PGN_PARAM, parameter index goes in the following byte
+
+const PARAM_TIMEOUT: Duration = Duration::from_millis(250); // time to wait for a parameter response
+const PARAM_RETRIES: u8 = 3; // attempts before giving up on a parameter
+const WRITE_SETTLE: Duration = Duration::from_millis(50); // time for the MCU to commit a write before reading back
+
+pub const UNCONFIRMED_FLAG: &str = "--unconfirmed"; // nothing is sent on the bus without it
+
+const MQTT_CLIENT_ID: &str = "can-mcu-params";
+const MQTT_REQUEST_TOPIC: &str = "mcu/params/request";
+const MQTT_RESPONSE_TOPIC: &str = "mcu/params/response";
+const PARAMS_DUMP_PATH: &str = "mcu_params.conf"; // where the mqtt "dump" command writes the configuration
+const PARAMS_BASELINE_PATH: &str = "mcu_params_baseline.conf"; // stored baseline used by "diff" and "apply"
+
+#[derive(Clone, Copy)]
+pub enum ParamKind {
+    Unsigned,
+    Signed,
+}
+
+pub struct Param {
+    pub name: &'static str,
+    pub index: u8, // parameter index in the MCU configuration table
+    pub kind: ParamKind,
+    pub guess: &'static str, // what the index is thought to hold, not confirmed
+}
+
+// MCU configuration table, the request, response and write layout and these indexes have not been confirmed against
+// vendor documentation or a bus capture, so parameters are named by index and values are raw words
+pub const PARAMS: [Param; 12] = [
+    Param { name: "param_01", index: 0x01, kind: ParamKind::Unsigned, guess: "cell count" },
+    Param { name: "param_02", index: 0x02, kind: ParamKind::Unsigned, guess: "thermistor count" },
+    Param { name: "param_10", index: 0x10, kind: ParamKind::Unsigned, guess: "cell low voltage cutoff, 0.1 mV" },
+    Param { name: "param_11", index: 0x11, kind: ParamKind::Unsigned, guess: "cell high voltage cutoff, 0.1 mV" },
+    Param { name: "param_12", index: 0x12, kind: ParamKind::Unsigned, guess: "cell low voltage recover, 0.1 mV" },
+    Param { name: "param_13", index: 0x13, kind: ParamKind::Unsigned, guess: "cell high voltage recover, 0.1 mV" },
+    Param { name: "param_14", index: 0x14, kind: ParamKind::Unsigned, guess: "cell balance voltage, 0.1 mV" },
+    Param { name: "param_20", index: 0x20, kind: ParamKind::Signed, guess: "thermistor low alarm, C" },
+    Param { name: "param_21", index: 0x21, kind: ParamKind::Signed, guess: "thermistor high alarm, C" },
+    Param { name: "param_30", index: 0x30, kind: ParamKind::Unsigned, guess: "pack capacity, 0.1 kWh" },
+    Param { name: "param_31", index: 0x31, kind: ParamKind::Unsigned, guess: "max charge voltage, 0.1 V" },
+    Param { name: "param_32", index: 0x32, kind: ParamKind::Unsigned, guess: "max charge current, 0.1 A" },
+];
+
+pub fn find_param(name: &str) -> Option<&'static Param> {
+    PARAMS.iter().find(|p| p.name == name)
+}
+
+fn raw_to_value(param: &Param, lo: u8, hi: u8) -> i32 {
+    match param.kind {
+        ParamKind::Unsigned => bytes_to_word_unsigned(lo, hi) as i32,
+        ParamKind::Signed => bytes_to_word_signed(lo, hi) as i32,
+    }
+}
+
+fn value_to_raw(param: &Param, value: i32) -> Result<[u8; 2]> {
+    let bytes = match param.kind {
+        ParamKind::Unsigned => u16::try_from(value).map(|v| v.to_le_bytes()),
+        ParamKind::Signed => i16::try_from(value).map(|v| v.to_le_bytes()),
+    };
+
+    bytes.map_err(|_| anyhow!("{} value {value} out of range", param.name))
+}
+
+// read a single parameter from the mcu, retrying on timeout
+pub fn read_param(sock: &CanSocket, param: &Param) -> Result<i32> {
+    let request = [PGN_PARAM[0], PGN_PARAM[1], param.index, 0x00];
+    let frame = CanFrame::new(ExtendedId::new(EID_REQUEST_READ).unwrap(), &request).expect("Failed to create frame");
+
+    for _ in 0..PARAM_RETRIES {
+        sock.write_frame(&frame)?;
+
+        if let Some(value) = wait_for_param(sock, param)? {
+            return Ok(value);
+        }
+    }
+
+    Err(anyhow!("No response for parameter {}", param.name))
+}
+
+// write a single parameter to the mcu and verify it by reading it back
+pub fn write_param(sock: &CanSocket, param: &Param, value: i32) -> Result<i32> {
+    let raw = value_to_raw(param, value)?;
+    let request = [param.index, 0x00, raw[0], raw[1]];
+    let frame = CanFrame::new(ExtendedId::new(EID_REQUEST_WRITE).unwrap(), &request).expect("Failed to create frame");
+
+    sock.write_frame(&frame)?;
+    thread::sleep(WRITE_SETTLE);
+
+    let read_back = read_param(sock, param)?;
+    if read_back != value {
+        bail!("Verify failed for {}: wrote {value}, read back {read_back}", param.name);
+    }
+
+    Ok(read_back)
+}
+
+fn wait_for_param(sock: &CanSocket, param: &Param) -> Result<Option<i32>> {
+    let deadline = Instant::now() + PARAM_TIMEOUT;
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        sock.set_read_timeout(remaining)?;
+
+        let frame = match sock.read_frame() {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let message = frame.data();
+        if frame.raw_id() == EID_PARAM_RESPONSE && message.len() >= 4 && message[0] == param.index {
+            return Ok(Some(raw_to_value(param, message[2], message[3])));
+        }
+    }
+
+    Ok(None)
+}
+
+// read the full configuration table, in table order
+pub fn read_config(sock: &CanSocket) -> Result<Vec<(&'static str, i32)>> {
+    let mut config = Vec::new();
+
+    for param in PARAMS.iter() {
+        config.push((param.name, read_param(sock, param)?));
+    }
+
+    Ok(config)
+}
+
+pub fn format_config(config: &[(&str, i32)]) -> String {
+    let mut contents = "# Thunderstruck MCU configuration, raw values, the meanings are unconfirmed guesses\n".to_string();
+
+    for (name, value) in config {
+        let guess = find_param(name).map(|p| p.guess).unwrap_or("");
+        contents.push_str(format!("{name}={value} # {guess}\n").as_str());
+    }
+
+    contents
+}
+
+// parse a configuration file of name=value lines, # starts a comment
+pub fn parse_config(contents: &str) -> Result<Vec<(&'static str, i32)>> {
+    let mut config = Vec::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, value) = line.split_once('=').ok_or_else(|| anyhow!("Line {}: expected name=value", line_number + 1))?;
+        let param = find_param(name.trim()).ok_or_else(|| anyhow!("Line {}: unknown parameter {}", line_number + 1, name.trim()))?;
+        let value: i32 = value.trim().parse().map_err(|_| anyhow!("Line {}: invalid value {}", line_number + 1, value.trim()))?;
+
+        config.push((param.name, value));
+    }
+
+    Ok(config)
+}
+
+pub fn load_config(path: &str) -> Result<Vec<(&'static str, i32)>> {
+    let contents = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {path}: {e}"))?;
+    parse_config(&contents)
+}
+
+// list parameters that differ between the mcu and a baseline
+pub fn diff_config(current: &[(&'static str, i32)], baseline: &[(&'static str, i32)]) -> Result<Vec<(&'static Param, i32, i32)>> {
+    let mut changes = Vec::new();
+
+    for (name, wanted) in baseline {
+        let param = find_param(name).ok_or_else(|| anyhow!("Unknown parameter {name}"))?;
+        let actual = current.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+
+        if let Some(actual) = actual {
+            if actual != *wanted {
+                changes.push((param, actual, *wanted));
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+fn format_diff(changes: &[(&Param, i32, i32)]) -> String {
+    if changes.is_empty() {
+        return "No differences".to_string();
+    }
+
+    let mut output = String::new();
+    for (param, actual, wanted) in changes {
+        output.push_str(format!("{}: {actual} -> {wanted}\n", param.name).as_str());
+    }
+    output.pop();
+
+    output
+}
+
+// write every parameter that differs from the baseline, returning a report of what changed
+pub fn apply_config(sock: &CanSocket, baseline: &[(&'static str, i32)]) -> Result<String> {
+    let current = read_config(sock)?;
+    let changes = diff_config(&current, baseline)?;
+
+    let mut report = String::new();
+    for (param, actual, wanted) in changes.iter() {
+        let written = write_param(sock, param, *wanted)?;
+        report.push_str(format!("{}: {actual} -> {written}\n", param.name).as_str());
+    }
+
+    if report.is_empty() {
+        return Ok("No differences".to_string());
+    }
+    report.pop();
+
+    Ok(report)
+}
+
+// ev-mcu params --unconfirmed <dump|diff|apply|get|set> ...
+pub fn run_cli(args: &[String]) -> Result<()> {
+    let mut args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+    // the frame layout is a guess, so sending anything on the vehicle bus has to be asked for
+    let Some(i) = args.iter().position(|a| *a == UNCONFIRMED_FLAG) else {
+        bail!("The MCU parameter frames are unconfirmed, add {UNCONFIRMED_FLAG} to send them anyway");
+    };
+    args.remove(i);
+
+    let mut iface = CAN_INTERFACE;
+    if let Some(i) = args.iter().position(|a| *a == "--iface") {
+        iface = args.get(i + 1).copied().ok_or_else(|| anyhow!("--iface requires an interface name"))?;
+        args.drain(i..i + 2);
+    }
+
+    let sock = CanSocket::open(iface)?;
+
+    match args.as_slice() {
+        ["dump"] => print!("{}", format_config(&read_config(&sock)?)),
+        ["dump", path] => {
+            fs::write(path, format_config(&read_config(&sock)?))?;
+            println!("Configuration written to {path}");
+        }
+        ["diff", path] => println!("{}", format_diff(&diff_config(&read_config(&sock)?, &load_config(path)?)?)),
+        ["apply", path] => println!("{}", apply_config(&sock, &load_config(path)?)?),
+        ["get", name] => {
+            let param = find_param(name).ok_or_else(|| anyhow!("Unknown parameter {name}"))?;
+            println!("{}={} # {}", param.name, read_param(&sock, param)?, param.guess);
+        }
+        ["set", name, value] => {
+            let param = find_param(name).ok_or_else(|| anyhow!("Unknown parameter {name}"))?;
+            let value: i32 = value.parse().map_err(|_| anyhow!("Invalid value {value}"))?;
+            println!("{}={}", param.name, write_param(&sock, param, value)?);
+        }
+        _ => bail!("Usage: ev-mcu params {UNCONFIRMED_FLAG} [--iface can0] <dump [file] | diff <baseline> | apply <file> | get <name> | set <name> <value>>"),
+    }
+
+    Ok(())
+}
+
+// serve parameter requests over mqtt on a separate client and can socket, only started with --unconfirmed
+pub fn spawn_mqtt_api(iface: String) {
+    thread::spawn(move || {
+        let sock = CanSocket::open(&iface).expect("Failed to open socket");
+
+        let mqtt_client = open_mqtt_connection(MQTT_CLIENT_ID);
+        let rx = mqtt_client.start_consuming();
+        subscribe_requests(&mqtt_client);
+
+        for msg in rx.iter() {
+            if let Some(msg) = msg {
+                let request = msg.payload_str().to_string();
+                println!("Parameter request: {request}");
+
+                let response = match handle_request(&sock, request.trim()) {
+                    Ok(r) => r,
+                    Err(e) => format!("Error: {e}"),
+                };
+                write_mqtt_message(&mqtt_client, MQTT_RESPONSE_TOPIC, response.as_str());
+            } else if !mqtt_client.is_connected() {
+                println!("Lost connection to mqtt broker");
+                while mqtt_client.reconnect().is_err() {
+                    thread::sleep(Duration::from_millis(5000));
+                }
+                println!("Reconnected to mqtt broker");
+                subscribe_requests(&mqtt_client);
+            }
+        }
+    });
+}
+
+fn subscribe_requests(mqtt_client: &Client) {
+    if let Err(e) = mqtt_client.subscribe(MQTT_REQUEST_TOPIC, 1) {
+        println!("Error subscribing to {MQTT_REQUEST_TOPIC}: {:?}", e);
+    }
+}
+
+// requests: dump | diff | apply | get <name> | set <name> <value>
+fn handle_request(sock: &CanSocket, request: &str) -> Result<String> {
+    let args: Vec<&str> = request.split_whitespace().collect();
+
+    match args.as_slice() {
+        ["dump"] => {
+            let contents = format_config(&read_config(sock)?);
+            fs::write(PARAMS_DUMP_PATH, &contents)?;
+            Ok(contents)
+        }
+        ["diff"] => Ok(format_diff(&diff_config(&read_config(sock)?, &load_config(PARAMS_BASELINE_PATH)?)?)),
+        ["apply"] => apply_config(sock, &load_config(PARAMS_BASELINE_PATH)?),
+        ["get", name] => {
+            let param = find_param(name).ok_or_else(|| anyhow!("Unknown parameter {name}"))?;
+            Ok(format!("{}={}", param.name, read_param(sock, param)?))
+        }
+        ["set", name, value] => {
+            let param = find_param(name).ok_or_else(|| anyhow!("Unknown parameter {name}"))?;
+            let value: i32 = value.parse().map_err(|_| anyhow!("Invalid value {value}"))?;
+            Ok(format!("{}={}", param.name, write_param(sock, param, value)?))
+        }
+        _ => bail!("Unknown request: {request}"),
+    }
+}
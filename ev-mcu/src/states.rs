@@ -0,0 +1,148 @@
+use std::fmt;
+
+// charger state reported in PGN_MCUSUM byte 4
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChargeState {
+    Standby,
+    Startup,
+    Warmdown,
+    Bulk,
+    Finish,
+    Float,
+    TopBalance,
+    Unknown(u8), // value not in the mcu documentation, keeps the raw code
+}
+
+// charge plug state reported in PGN_MCUSUM byte 5
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlugState {
+    NotDetected, // mcu reports the plug state as unknown
+    Disconnected,
+    Connected,
+    Locked,
+    WaitingForDisc,
+    Active,
+    Unknown(u8), // value not in the mcu documentation, keeps the raw code
+}
+
+impl ChargeState {
+    pub fn from_code(code: u8) -> ChargeState {
+        match code {
+            0 => ChargeState::Standby,
+            1 => ChargeState::Startup,
+            2 => ChargeState::Warmdown,
+            10 => ChargeState::Bulk,
+            11 => ChargeState::Finish,
+            12 => ChargeState::Float,
+            13 => ChargeState::TopBalance,
+            _ => ChargeState::Unknown(code),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            ChargeState::Standby => 0,
+            ChargeState::Startup => 1,
+            ChargeState::Warmdown => 2,
+            ChargeState::Bulk => 10,
+            ChargeState::Finish => 11,
+            ChargeState::Float => 12,
+            ChargeState::TopBalance => 13,
+            ChargeState::Unknown(code) => *code,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ChargeState::Standby => "Standby",
+            ChargeState::Startup => "Startup",
+            ChargeState::Warmdown => "Warmdown",
+            ChargeState::Bulk => "Bulk",
+            ChargeState::Finish => "Finish",
+            ChargeState::Float => "Float",
+            ChargeState::TopBalance => "Top Balance",
+            ChargeState::Unknown(_) => "unknown",
+        }
+    }
+
+    pub fn is_charging(&self) -> bool {
+        matches!(self, ChargeState::Bulk | ChargeState::Finish | ChargeState::Float | ChargeState::TopBalance)
+    }
+}
+
+impl PlugState {
+    pub fn from_code(code: u8) -> PlugState {
+        match code {
+            0 => PlugState::NotDetected,
+            1 => PlugState::Disconnected,
+            2 => PlugState::Connected,
+            3 => PlugState::Locked,
+            4 => PlugState::WaitingForDisc,
+            5 => PlugState::Active,
+            _ => PlugState::Unknown(code),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            PlugState::NotDetected => 0,
+            PlugState::Disconnected => 1,
+            PlugState::Connected => 2,
+            PlugState::Locked => 3,
+            PlugState::WaitingForDisc => 4,
+            PlugState::Active => 5,
+            PlugState::Unknown(code) => *code,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            PlugState::NotDetected => "Unknown",
+            PlugState::Disconnected => "Disconnected",
+            PlugState::Connected => "Connected",
+            PlugState::Locked => "Locked",
+            PlugState::WaitingForDisc => "Waiting For Disc",
+            PlugState::Active => "Active",
+            PlugState::Unknown(_) => "unknown",
+        }
+    }
+
+    pub fn is_plugged_in(&self) -> bool {
+        matches!(self, PlugState::Connected | PlugState::Locked | PlugState::WaitingForDisc | PlugState::Active)
+    }
+}
+
+// a state with a raw mcu code, for logging transitions
+pub trait StateCode: fmt::Display {
+    fn raw_code(&self) -> u8;
+}
+
+impl StateCode for ChargeState {
+    fn raw_code(&self) -> u8 {
+        self.code()
+    }
+}
+
+impl StateCode for PlugState {
+    fn raw_code(&self) -> u8 {
+        self.code()
+    }
+}
+
+impl fmt::Display for ChargeState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChargeState::Unknown(code) => write!(f, "unknown({code})"),
+            _ => write!(f, "{}", self.label()),
+        }
+    }
+}
+
+impl fmt::Display for PlugState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlugState::Unknown(code) => write!(f, "unknown({code})"),
+            _ => write!(f, "{}", self.label()),
+        }
+    }
+}
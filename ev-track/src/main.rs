@@ -0,0 +1,221 @@
+use mqtt::Client;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+extern crate paho_mqtt as mqtt;
+
+mod track;
+
+use track::Point;
+
+const MQTT_IP: &str = "tcp://127.0.0.1:1883";
+const MQTT_CLIENT_ID: &str = "track";
+const TRACK_LOG_PATH: &str = "track.log"; // every recorded point, csv
+const DRIVES_DIR: &str = "drives"; // gpx and geojson of each drive
+const MAX_AGE: Duration = Duration::from_secs(10); // older speed, altitude, current and soc values are left out of a point
+
+const POSITION_TOPIC: &str = "live/gps/position";
+const FIX_TIME_TOPIC: &str = "live/gps/fix_time"; // gps time of the next position, rfc3339
+const SPEED_TOPIC: &str = "live/gps/speed";
+const ALTITUDE_TOPIC: &str = "live/gps/altitude";
+const PACK_CURRENT_TOPIC: &str = "live/mcu/pack_current";
+const SOC_TOPIC: &str = "live/mcu/soc";
+const TRIP_ACTIVE_TOPIC: &str = "live/trip/active"; // 1 at the start of a trip, 0 at the end, retained
+const TOPICS: [&str; 7] = [POSITION_TOPIC, FIX_TIME_TOPIC, SPEED_TOPIC, ALTITUDE_TOPIC, PACK_CURRENT_TOPIC, SOC_TOPIC, TRIP_ACTIVE_TOPIC];
+
+// most recent value of a topic and when it arrived
+type Latest = Option<(f64, Instant)>;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 {
+        let result = match args.get(1).map(|a| a.as_str()) {
+            Some("export") if args.len() == 5 => export(&args[2], &args[3], &args[4]),
+            _ => Err("Usage: ev-track [export <from> <to> <file.gpx|file.geojson>]".to_string()),
+        };
+
+        if let Err(e) = result {
+            println!("{e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    record();
+}
+
+// log every position and write each drive to gpx and geojson when its trip ends
+fn record() {
+    let mqtt_client = open_mqtt_connection();
+    let rx = mqtt_client.start_consuming();
+    subscribe_topics(&mqtt_client);
+
+    let mut speed: Latest = None;
+    let mut altitude: Latest = None;
+    let mut pack_current: Latest = None;
+    let mut soc: Latest = None;
+    let mut fix_time: Option<(DateTime<Utc>, Instant)> = None;
+    let mut drive: Option<Vec<Point>> = None; // points of the trip in progress
+
+    for msg in rx.iter() {
+        let Some(msg) = msg else {
+            if !mqtt_client.is_connected() {
+                println!("Lost connection to mqtt broker");
+                while mqtt_client.reconnect().is_err() {
+                    thread::sleep(Duration::from_millis(5000));
+                }
+                println!("Reconnected to mqtt broker");
+                subscribe_topics(&mqtt_client);
+            }
+            continue;
+        };
+
+        let payload = msg.payload_str();
+        let value = payload.trim().parse::<f64>().ok().map(|v| (v, Instant::now()));
+
+        match msg.topic() {
+            POSITION_TOPIC => {
+                let Some((latitude, longitude)) = parse_position(&payload) else { continue };
+                let fresh = |latest: Latest| latest.filter(|(_, time)| time.elapsed() <= MAX_AGE).map(|(v, _)| v);
+
+                // the gps time of the fix, the system clock when ev-gps didn't send one
+                let time = fix_time.take().filter(|(_, received)| received.elapsed() <= MAX_AGE).map_or_else(Utc::now, |(time, _)| time);
+
+                let point = Point {
+                    time,
+                    latitude,
+                    longitude,
+                    speed: fresh(speed),
+                    altitude: fresh(altitude),
+                    pack_current: fresh(pack_current),
+                    soc: fresh(soc),
+                };
+
+                if let Err(e) = track::append_log(TRACK_LOG_PATH, &point) {
+                    println!("{e}");
+                }
+                if let Some(drive) = drive.as_mut() {
+                    drive.push(point);
+                }
+            }
+            FIX_TIME_TOPIC => fix_time = DateTime::parse_from_rfc3339(payload.trim()).ok().map(|t| (t.with_timezone(&Utc), Instant::now())),
+            SPEED_TOPIC => speed = value,
+            ALTITUDE_TOPIC => altitude = value,
+            PACK_CURRENT_TOPIC => pack_current = value,
+            SOC_TOPIC => soc = value,
+            TRIP_ACTIVE_TOPIC => match (payload.trim(), drive.is_some()) {
+                ("1", false) => {
+                    println!("Recording drive");
+                    drive = Some(Vec::new());
+                }
+                ("0", true) => {
+                    if let Some(points) = drive.take() {
+                        save_drive(&points);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+fn subscribe_topics(mqtt_client: &Client) {
+    for topic in TOPICS {
+        if let Err(e) = mqtt_client.subscribe(topic, 0) {
+            println!("Error subscribing to {topic}: {:?}", e);
+        }
+    }
+}
+
+// "latitude,longitude" as published by ev-gps
+fn parse_position(payload: &str) -> Option<(f64, f64)> {
+    let (latitude, longitude) = payload.trim().split_once(',')?;
+    Some((latitude.parse().ok()?, longitude.parse().ok()?))
+}
+
+fn save_drive(points: &[Point]) {
+    let Some(first) = points.first() else {
+        println!("Drive ended without any positions");
+        return;
+    };
+
+    let name = first.time.format("%Y-%m-%d_%H%M%S").to_string();
+    if let Err(e) = fs::create_dir_all(DRIVES_DIR) {
+        println!("Failed to create {DRIVES_DIR}: {e}");
+        return;
+    }
+
+    for extension in ["gpx", "geojson"] {
+        let path = format!("{DRIVES_DIR}/{name}.{extension}");
+        match track::export(&path, &name, points) {
+            Ok(_) => println!("Saved {} points to {path}", points.len()),
+            Err(e) => println!("{e}"),
+        }
+    }
+}
+
+// export the logged points between two utc times
+fn export(from: &str, to: &str, path: &str) -> Result<(), String> {
+    let from = parse_time(from)?;
+    let to = parse_time(to)?;
+    if from > to {
+        return Err(format!("Start {from} is after end {to}"));
+    }
+
+    let points = track::read_log(TRACK_LOG_PATH, from, to)?;
+    if points.is_empty() {
+        return Err(format!("No points in {TRACK_LOG_PATH} between {from} and {to}"));
+    }
+
+    let name = format!("{} to {}", from.format("%Y-%m-%d %H:%M"), to.format("%Y-%m-%d %H:%M"));
+    track::export(path, &name, &points)?;
+
+    println!("Exported {} points to {path}", points.len());
+    Ok(())
+}
+
+// rfc3339, "2024-06-01T14:30:00", "2024-06-01 14:30" or "2024-06-01", times without an offset are utc
+fn parse_time(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(time.and_utc());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+
+    Err(format!("Invalid time {text}, use e.g. 2024-06-01T14:30:00Z, 2024-06-01 14:30 or 2024-06-01"))
+}
+
+fn open_mqtt_connection() -> Client {
+    let create_opts = mqtt::CreateOptionsBuilder::new()
+        .server_uri(MQTT_IP)
+        .client_id(MQTT_CLIENT_ID.to_string())
+        .finalize();
+
+    // Create a client.
+    let mqtt_client = mqtt::Client::new(create_opts).unwrap_or_else(|err| {
+        println!("Error creating the client: {:?}", err);
+        process::exit(1);
+    });
+
+    // Define the set of options for the connection.
+    let conn_opts = mqtt::ConnectOptionsBuilder::new()
+        .keep_alive_interval(Duration::from_secs(20))
+        .clean_session(true)
+        .finalize();
+
+    // Connect and wait for it to complete or fail.
+    if let Err(e) = mqtt_client.connect(conn_opts) {
+        println!("Unable to connect:\n\t{:?}", e);
+        process::exit(1);
+    }
+
+    mqtt_client
+}
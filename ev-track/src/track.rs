@@ -0,0 +1,164 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+pub const LOG_HEADER: &str = "timestamp_ms,latitude,longitude,speed,altitude,pack_current,soc";
+
+// one recorded position, values other than the position are missing when they were not received recently
+pub struct Point {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub speed: Option<f64>, // km/h
+    pub altitude: Option<f64>, // m
+    pub pack_current: Option<f64>, // A, negative is discharge
+    pub soc: Option<f64>, // %
+}
+
+fn optional(value: Option<f64>, decimals: usize) -> String {
+    value.map(|v| format!("{v:.decimals$}")).unwrap_or_default()
+}
+
+impl Point {
+    // csv line for the local track log
+    pub fn log_line(&self) -> String {
+        format!(
+            "{},{:.8},{:.8},{},{},{},{}",
+            self.time.timestamp_millis(),
+            self.latitude,
+            self.longitude,
+            optional(self.speed, 1),
+            optional(self.altitude, 1),
+            optional(self.pack_current, 1),
+            optional(self.soc, 0)
+        )
+    }
+
+    pub fn parse_log_line(line: &str) -> Option<Point> {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 7 {
+            return None;
+        }
+        let value = |i: usize| fields[i].parse::<f64>().ok();
+
+        Some(Point {
+            time: DateTime::from_timestamp_millis(fields[0].parse().ok()?)?,
+            latitude: value(1)?,
+            longitude: value(2)?,
+            speed: value(3),
+            altitude: value(4),
+            pack_current: value(5),
+            soc: value(6),
+        })
+    }
+
+    fn timestamp(&self) -> String {
+        self.time.to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+}
+
+// append a point to the log, writing the header to a new file
+pub fn append_log(path: &str, point: &Point) -> Result<(), String> {
+    let new_file = fs::metadata(path).is_err();
+    let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+
+    if new_file {
+        writeln!(file, "{LOG_HEADER}").map_err(|e| format!("{e}"))?;
+    }
+    writeln!(file, "{}", point.log_line()).map_err(|e| format!("{e}"))
+}
+
+// points in the log between two times
+pub fn read_log(path: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Point>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+
+    Ok(contents.lines().skip(1).filter_map(Point::parse_log_line).filter(|p| p.time >= from && p.time <= to).collect())
+}
+
+// write gpx or geojson depending on the file extension
+pub fn export(path: &str, name: &str, points: &[Point]) -> Result<(), String> {
+    let contents = if path.ends_with(".gpx") {
+        gpx(name, points)
+    } else if path.ends_with(".geojson") || path.ends_with(".json") {
+        geojson(name, points)
+    } else {
+        return Err(format!("Unknown format for {path}, use .gpx or .geojson"));
+    };
+
+    let mut file = BufWriter::new(File::create(path).map_err(|e| format!("Failed to create {path}: {e}"))?);
+    file.write_all(contents.as_bytes()).map_err(|e| format!("Failed to write {path}: {e}"))
+}
+
+// drive names can hold any text, escaped for the gpx name element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+// and for a json string
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// gpx 1.1 track, speed, pack current and soc go in extensions as gpx has no elements for them
+fn gpx(name: &str, points: &[Point]) -> String {
+    let mut gpx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str("<gpx version=\"1.1\" creator=\"ev-track\" xmlns=\"http://www.topografix.com/GPX/1/1\" xmlns:ev=\"urn:ev-track\">\n");
+    gpx.push_str(&format!("  <trk>\n    <name>{}</name>\n    <trkseg>\n", xml_escape(name)));
+
+    for point in points.iter() {
+        gpx.push_str(&format!("      <trkpt lat=\"{:.8}\" lon=\"{:.8}\">\n", point.latitude, point.longitude));
+        if let Some(altitude) = point.altitude {
+            gpx.push_str(&format!("        <ele>{altitude:.1}</ele>\n"));
+        }
+        gpx.push_str(&format!("        <time>{}</time>\n", point.timestamp()));
+
+        let extensions: Vec<String> = [("speed", point.speed, 1), ("pack_current", point.pack_current, 1), ("soc", point.soc, 0)]
+            .iter()
+            .filter_map(|(tag, value, decimals)| value.map(|v| format!("<ev:{tag}>{v:.decimals$}</ev:{tag}>")))
+            .collect();
+        if !extensions.is_empty() {
+            gpx.push_str(&format!("        <extensions>{}</extensions>\n", extensions.join("")));
+        }
+        gpx.push_str("      </trkpt>\n");
+    }
+
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    gpx
+}
+
+// one LineString feature, per point values are arrays in the properties in the same order as the coordinates
+fn geojson(name: &str, points: &[Point]) -> String {
+    let json = |value: Option<f64>, decimals: usize| value.map(|v| format!("{v:.decimals$}")).unwrap_or_else(|| "null".to_string());
+
+    let coordinates: Vec<String> = points
+        .iter()
+        .map(|p| match p.altitude {
+            Some(altitude) => format!("[{:.8},{:.8},{altitude:.1}]", p.longitude, p.latitude),
+            None => format!("[{:.8},{:.8}]", p.longitude, p.latitude),
+        })
+        .collect();
+    let times: Vec<String> = points.iter().map(|p| format!("\"{}\"", p.timestamp())).collect();
+    let speeds: Vec<String> = points.iter().map(|p| json(p.speed, 1)).collect();
+    let pack_currents: Vec<String> = points.iter().map(|p| json(p.pack_current, 1)).collect();
+    let socs: Vec<String> = points.iter().map(|p| json(p.soc, 0)).collect();
+
+    format!(
+        "{{\"type\":\"FeatureCollection\",\"features\":[{{\"type\":\"Feature\",\"geometry\":{{\"type\":\"LineString\",\"coordinates\":[{}]}},\"properties\":{{\"name\":\"{}\",\"times\":[{}],\"speed\":[{}],\"pack_current\":[{}],\"soc\":[{}]}}}}]}}\n",
+        coordinates.join(","),
+        json_escape(name),
+        times.join(","),
+        speeds.join(","),
+        pack_currents.join(","),
+        socs.join(",")
+    )
+}